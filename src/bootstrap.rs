@@ -1,14 +1,102 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tracing::{error, info};
 
+use crate::cluster::Cluster;
 use crate::config::Config;
+use crate::counters::Counters;
+use crate::file_list::{fetch_file_list, last_modified};
+use crate::server::{router, start_server};
+use crate::storage::{MultiStorage, Storage};
+use crate::sync::Syncer;
+use crate::tls::{request_cert, start_byoc_watcher, start_cert_renewal, CertKeyPair};
 use crate::token::TokenManager;
+use crate::utils::build_client;
 use crate::PKG_VERSION;
 
-pub async fn bootstrap(config: &Config) {
+pub async fn bootstrap(config: &Config) -> Result<()> {
     info!("Booting {PKG_VERSION}");
+    if config.byoc.is_some() && config.host.is_none() {
+        bail!("`host` must be set when using your own certificate");
+    }
+    if config.storage.is_empty() {
+        bail!("At least one storage must be configured");
+    }
     let token_manager =
-        TokenManager::new(&config.cluster_id, &config.cluster_secret, &config.bmclapi);
-    if let Err(err) = token_manager.fetch_token().await {
-        error!("Failed to fetch token: {}", err);
+        TokenManager::new(&config.cluster_id, &config.cluster_secret, &config.bmclapi).await?;
+
+    let storage: Arc<dyn Storage> = Arc::new(MultiStorage::new(config.storage.clone()));
+    storage.init().await?;
+    storage.validate().await?;
+
+    let client = build_client(&config.bmclapi);
+    let files = fetch_file_list(&client, &token_manager.current_token(), None).await?;
+    let last_modified = last_modified(&files);
+    let syncer = Arc::new(Syncer::new(
+        client,
+        token_manager.subscribe(),
+        storage.clone(),
+        config.sync_concurrency,
+    ));
+    syncer.sync(files).await?;
+
+    let counters = Arc::new(Counters::default());
+    let cluster = Cluster::connect(config, token_manager.clone(), counters.clone()).await?;
+    let (cert_rx, cert_task) = match &config.byoc {
+        Some(byoc) => {
+            let (cert_tx, cert_rx) = watch::channel(CertKeyPair::load(byoc).await?);
+            (cert_rx, start_byoc_watcher(byoc.clone(), cert_tx))
+        }
+        None => {
+            let (cert_tx, cert_rx) = watch::channel(request_cert(&cluster).await?);
+            (cert_rx, start_cert_renewal(cluster.clone(), cert_tx))
+        }
     };
+    let server = start_server(
+        config.port,
+        router(storage, &config.cluster_secret, counters),
+        cert_rx,
+    )
+    .await?;
+
+    cluster.enable().await?;
+    let keep_alive = cluster.clone().start_keep_alive();
+    let sync_scheduler = syncer.start_scheduler(
+        Duration::from_secs(config.sync_interval * 60),
+        last_modified,
+    );
+
+    shutdown_signal().await?;
+    info!("Shutting down");
+    keep_alive.abort();
+    sync_scheduler.abort();
+    cert_task.abort();
+    if let Err(err) = cluster.disable().await {
+        error!("Failed to disable cluster: {}", err);
+    }
+    server
+        .shutdown(Duration::from_secs(config.shutdown_timeout))
+        .await;
+
+    Ok(())
+}
+
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
 }
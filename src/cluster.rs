@@ -0,0 +1,643 @@
+use std::cmp::min;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{SecondsFormat, Utc};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use rust_socketio::asynchronous::{Client, ClientBuilder};
+use rust_socketio::{Payload, TransportType};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::counters::Counters;
+use crate::tls::CertKeyPair;
+use crate::token::TokenManager;
+use crate::PKG_VERSION;
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+const ENABLE_TIMEOUT: Duration = Duration::from_secs(300);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_KEEP_ALIVE_FAILURES: u32 = 3;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Reasons to reconnect, tagged with the generation of the socket they came
+/// from so events from a socket we already replaced are ignored.
+type ReconnectSender = mpsc::UnboundedSender<(u64, String)>;
+
+pub struct Cluster {
+    base_url: String,
+    enable_payload: Value,
+    token_manager: Arc<TokenManager>,
+    counters: Arc<Counters>,
+    socket: RwLock<Client>,
+    generation: AtomicU64,
+    reconnect_tx: ReconnectSender,
+    reconnect_rx: Mutex<mpsc::UnboundedReceiver<(u64, String)>>,
+}
+
+impl Cluster {
+    pub async fn connect(
+        config: &Config,
+        token_manager: Arc<TokenManager>,
+        counters: Arc<Counters>,
+    ) -> Result<Arc<Self>> {
+        let (reconnect_tx, reconnect_rx) = mpsc::unbounded_channel();
+        let socket = Self::connect_socket(
+            &config.bmclapi,
+            &token_manager.current_token(),
+            0,
+            reconnect_tx.clone(),
+        )
+        .await?;
+
+        Ok(Arc::new(Self {
+            base_url: config.bmclapi.clone(),
+            enable_payload: enable_payload(config),
+            token_manager,
+            counters,
+            socket: RwLock::new(socket),
+            generation: AtomicU64::new(0),
+            reconnect_tx,
+            reconnect_rx: Mutex::new(reconnect_rx),
+        }))
+    }
+
+    async fn connect_socket(
+        base_url: &str,
+        token: &str,
+        generation: u64,
+        reconnect_tx: ReconnectSender,
+    ) -> Result<Client> {
+        info!("Connecting to {base_url}");
+        let socket = ClientBuilder::new(base_url)
+            .transport_type(TransportType::Websocket)
+            .auth(json!({ "token": token }))
+            .on("error", reconnect_on("error", generation, &reconnect_tx))
+            .on(
+                "exception",
+                reconnect_on("exception", generation, &reconnect_tx),
+            )
+            .on("close", reconnect_on("close", generation, &reconnect_tx))
+            .on("message", |payload, _| {
+                async move { info!("Message from center: {:?}", payload) }.boxed()
+            })
+            .connect()
+            .await?;
+        info!("Connected to {base_url}");
+
+        Ok(socket)
+    }
+
+    pub async fn enable(&self) -> Result<()> {
+        info!("Enabling cluster");
+        let ack = self
+            .emit_with_ack("enable", self.enable_payload.clone(), ENABLE_TIMEOUT)
+            .await?;
+        if ack != Value::Bool(true) {
+            bail!("Center refused to enable cluster: {ack}");
+        }
+        info!("Cluster enabled");
+
+        Ok(())
+    }
+
+    pub async fn keep_alive(&self) -> Result<()> {
+        let snapshot = self.counters.snapshot();
+        let payload = json!({
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "hits": snapshot.hits,
+            "bytes": snapshot.bytes,
+        });
+        let ack = self
+            .emit_with_ack("keep-alive", payload, ACK_TIMEOUT)
+            .await?;
+        if ack.is_null() || ack == Value::Bool(false) {
+            bail!("Keep-alive was rejected by center");
+        }
+        // Only drop the reported traffic once the center has accepted it.
+        self.counters.subtract(snapshot);
+        debug!(
+            "Keep-alive acknowledged at {ack}: {} hits, {} bytes",
+            snapshot.hits, snapshot.bytes
+        );
+
+        Ok(())
+    }
+
+    /// Sends keep-alives and tears down, reconnects and re-enables the
+    /// cluster when the socket drops, the center reports an error, or too
+    /// many keep-alives fail in a row.
+    pub fn start_keep_alive(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut reconnect_rx = self.reconnect_rx.lock().await;
+            let mut interval = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+            // The first tick completes immediately, skip it since we just enabled.
+            interval.tick().await;
+            let mut failures = 0;
+            loop {
+                let reason = tokio::select! {
+                    _ = interval.tick() => match self.keep_alive().await {
+                        Ok(()) => {
+                            failures = 0;
+                            continue;
+                        }
+                        Err(err) => {
+                            failures += 1;
+                            warn!("Keep-alive failed ({failures}/{MAX_KEEP_ALIVE_FAILURES}): {}", err);
+                            if failures < MAX_KEEP_ALIVE_FAILURES {
+                                continue;
+                            }
+                            format!("{failures} keep-alives failed in a row")
+                        }
+                    },
+                    Some((generation, reason)) = reconnect_rx.recv() => {
+                        if generation != self.generation.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        reason
+                    }
+                };
+
+                warn!("Reconnecting to center: {reason}");
+                self.reconnect().await;
+                failures = 0;
+                interval.reset();
+            }
+        })
+    }
+
+    async fn reconnect(&self) {
+        // Bump the generation first so the close event of the old socket is
+        // ignored.
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = self.socket.read().await.disconnect().await {
+            debug!("Failed to disconnect: {}", err);
+        }
+
+        let mut retry_delay = MIN_RECONNECT_DELAY;
+        loop {
+            tokio::time::sleep(retry_delay).await;
+            match self.try_reconnect().await {
+                Ok(()) => {
+                    info!("Reconnected to center");
+                    return;
+                }
+                Err(err) => {
+                    retry_delay = min(retry_delay * 2, MAX_RECONNECT_DELAY);
+                    warn!(
+                        "Failed to reconnect, retrying in {:?}: {}",
+                        retry_delay, err
+                    );
+                }
+            }
+        }
+    }
+
+    async fn try_reconnect(&self) -> Result<()> {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let token = self.token_manager.refresh().await?;
+        let socket = Self::connect_socket(
+            &self.base_url,
+            &token,
+            generation,
+            self.reconnect_tx.clone(),
+        )
+        .await?;
+        *self.socket.write().await = socket;
+
+        if let Err(err) = self.enable().await {
+            let _ = self.socket.read().await.disconnect().await;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub async fn request_cert(&self) -> Result<CertKeyPair> {
+        info!("Requesting certificate from center");
+        let ack = self
+            .emit_with_ack("request-cert", Value::Null, ACK_TIMEOUT)
+            .await?;
+        let (Some(cert), Some(key)) = (
+            ack.get("cert").and_then(Value::as_str),
+            ack.get("key").and_then(Value::as_str),
+        ) else {
+            bail!("Unexpected request-cert ack: {ack}");
+        };
+
+        Ok(CertKeyPair {
+            cert: cert.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    pub async fn disable(&self) -> Result<()> {
+        info!("Disabling cluster");
+        let ack = self
+            .emit_with_ack("disable", Value::Null, ACK_TIMEOUT)
+            .await?;
+        if ack != Value::Bool(true) {
+            bail!("Center refused to disable cluster: {ack}");
+        }
+        self.socket.read().await.disconnect().await?;
+        info!("Cluster disabled");
+
+        Ok(())
+    }
+
+    async fn emit_with_ack(&self, event: &str, data: Value, timeout: Duration) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let socket = self.socket.read().await.clone();
+        socket
+            .emit_with_ack(event, data, timeout, move |payload, _| {
+                let tx = tx.clone();
+                async move {
+                    if let Some(tx) = tx.lock().await.take() {
+                        let _ = tx.send(payload);
+                    }
+                }
+                .boxed()
+            })
+            .await?;
+
+        // The ack callback is dropped without being called when the timeout elapses.
+        let payload = tokio::time::timeout(timeout, rx)
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for {event} ack"))?
+            .map_err(|_| anyhow!("No ack received for {event}"))?;
+
+        parse_ack(payload)
+    }
+}
+
+fn enable_payload(config: &Config) -> Value {
+    let storage = config
+        .storage
+        .iter()
+        .map(|storage| storage.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    json!({
+        "host": config.host,
+        "port": config.public_port.unwrap_or(config.port),
+        "version": PKG_VERSION,
+        "byoc": config.byoc.is_some(),
+        "noFastEnable": false,
+        "flavor": {
+            "runtime": "Rust",
+            "storage": storage,
+        },
+    })
+}
+
+fn reconnect_on(
+    event: &'static str,
+    generation: u64,
+    reconnect_tx: &ReconnectSender,
+) -> impl FnMut(Payload, Client) -> BoxFuture<'static, ()> + Send + Sync + 'static {
+    let reconnect_tx = reconnect_tx.clone();
+    move |payload, _| {
+        error!("Cluster socket {event}: {:?}", payload);
+        let _ = reconnect_tx.send((generation, format!("{event}: {payload:?}")));
+        async {}.boxed()
+    }
+}
+
+/// The center acks with a single `[err, ack]` array.
+fn parse_ack(payload: Payload) -> Result<Value> {
+    let values = match payload {
+        Payload::Text(values) => values,
+        payload => bail!("Unexpected ack payload: {:?}", payload),
+    };
+    let args = match values.as_slice() {
+        [Value::Array(args)] => args.clone(),
+        _ => values,
+    };
+    let mut args = args.into_iter();
+    let err = args.next().unwrap_or(Value::Null);
+    if !err.is_null() {
+        let message = err
+            .get("message")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| err.to_string());
+        bail!("Center replied with error: {message}");
+    }
+
+    Ok(args.next().unwrap_or(Value::Null))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex as StdMutex;
+
+    use bytes::Bytes;
+    use salvo::prelude::*;
+    use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
+
+    use super::*;
+    use crate::test_server;
+
+    #[derive(Default)]
+    struct CenterState {
+        tokens_issued: usize,
+        /// Auth token of every Socket.IO connection, in order.
+        connections: Vec<String>,
+        /// Pushes raw Engine.IO packets to each connection.
+        sockets: Vec<mpsc::UnboundedSender<String>>,
+        events: Vec<(String, Value)>,
+        /// Error messages to reply to events with instead of acking them.
+        errors: HashMap<String, String>,
+    }
+
+    /// Stand-in for the center: the token endpoints over HTTP and a
+    /// websocket-only Socket.IO server that acks like the center does.
+    #[derive(Clone, Default)]
+    struct MockCenter {
+        state: Arc<StdMutex<CenterState>>,
+    }
+
+    impl MockCenter {
+        fn count(&self, event: &str) -> usize {
+            let state = self.state.lock().unwrap();
+            state
+                .events
+                .iter()
+                .filter(|(name, _)| name == event)
+                .count()
+        }
+
+        fn fail(&self, event: &str, message: &str) {
+            let mut state = self.state.lock().unwrap();
+            state.errors.insert(event.to_string(), message.to_string());
+        }
+
+        async fn serve_socket(self, mut socket: WebSocket) {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            self.state.lock().unwrap().sockets.push(tx);
+            // Engine.IO handshake, pinging too rarely to matter in a test.
+            let open = json!({
+                "sid": "sid",
+                "upgrades": [],
+                "pingInterval": 300_000,
+                "pingTimeout": 300_000,
+                "maxPayload": 1_000_000,
+            });
+            if socket
+                .send(Message::text(format!("0{open}")))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            loop {
+                let packet = tokio::select! {
+                    Some(packet) = rx.recv() => packet,
+                    message = socket.recv() => {
+                        let Some(Ok(message)) = message else {
+                            return;
+                        };
+                        if message.is_close() {
+                            return;
+                        }
+                        let Some(reply) = message.to_str().ok().and_then(|text| self.reply(text))
+                        else {
+                            continue;
+                        };
+                        reply
+                    }
+                };
+                if socket.send(Message::text(packet)).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        /// Replies to a Socket.IO packet, which comes as an Engine.IO
+        /// message (`4`) holding a connect (`0`) or an event (`2`).
+        fn reply(&self, packet: &str) -> Option<String> {
+            let packet = packet.strip_prefix('4')?;
+            let mut state = self.state.lock().unwrap();
+            if let Some(auth) = packet.strip_prefix('0') {
+                let auth: Value = serde_json::from_str(auth).unwrap();
+                state
+                    .connections
+                    .push(auth["token"].as_str().unwrap().to_string());
+                let sid = format!("sid-{}", state.connections.len());
+                return Some(format!("40{}", json!({ "sid": sid })));
+            }
+
+            let event = packet.strip_prefix('2')?;
+            let (ack_id, args) = event.split_at(event.find('[')?);
+            let args: Vec<Value> = serde_json::from_str(args).unwrap();
+            let name = args[0].as_str().unwrap().to_string();
+            let data = args.get(1).cloned().unwrap_or(Value::Null);
+            let ack = match state.errors.get(&name) {
+                Some(message) => json!([{ "message": message }]),
+                None => {
+                    let ack = match name.as_str() {
+                        "keep-alive" => data["time"].clone(),
+                        "request-cert" => json!({ "cert": "cert", "key": "key" }),
+                        _ => json!(true),
+                    };
+                    json!([null, ack])
+                }
+            };
+            state.events.push((name, data));
+
+            Some(format!("43{ack_id}{}", json!([ack])))
+        }
+    }
+
+    #[handler]
+    impl MockCenter {
+        async fn handle(&self, req: &mut Request, res: &mut Response) {
+            let path = req.uri().path().to_string();
+            match path.as_str() {
+                "/openbmclapi-agent/challenge" => {
+                    res.render(Json(json!({ "challenge": "challenge" })));
+                }
+                "/openbmclapi-agent/token" => {
+                    let mut state = self.state.lock().unwrap();
+                    state.tokens_issued += 1;
+                    let token = format!("token-{}", state.tokens_issued);
+                    res.render(Json(json!({ "token": token, "ttl": 3_600_000 })));
+                }
+                _ => {
+                    let center = self.clone();
+                    WebSocketUpgrade::new()
+                        .upgrade(req, res, |socket| center.serve_socket(socket))
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    async fn start() -> (MockCenter, Arc<Cluster>, Arc<Counters>) {
+        let center = MockCenter::default();
+        let url = test_server::start(center.clone()).await;
+        let config: Config = toml::from_str(&format!(
+            "bmclapi = \"{url}\"\n\
+             cluster_id = \"cluster\"\n\
+             cluster_secret = \"secret\"\n\
+             storage = []"
+        ))
+        .unwrap();
+        let token_manager =
+            TokenManager::new(&config.cluster_id, &config.cluster_secret, &config.bmclapi)
+                .await
+                .unwrap();
+        let counters = Arc::new(Counters::default());
+        let cluster = Cluster::connect(&config, token_manager, counters.clone())
+            .await
+            .unwrap();
+
+        (center, cluster, counters)
+    }
+
+    #[tokio::test]
+    async fn enable_is_acked() {
+        let (center, cluster, _) = start().await;
+        cluster.enable().await.unwrap();
+
+        let state = center.state.lock().unwrap();
+        assert_eq!(state.connections, ["token-1"]);
+        let (event, payload) = &state.events[0];
+        assert_eq!(event, "enable");
+        assert_eq!(payload["version"], PKG_VERSION);
+        assert_eq!(payload["port"], 4000);
+        assert_eq!(payload["flavor"]["runtime"], "Rust");
+    }
+
+    #[tokio::test]
+    async fn enable_fails_on_error_reply() {
+        let (center, cluster, _) = start().await;
+        center.fail("enable", "cluster is banned");
+
+        let err = cluster.enable().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Center replied with error: cluster is banned"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_alive_subtracts_acked_counters() {
+        let (center, cluster, counters) = start().await;
+        counters.add_hit();
+        counters.add_hit();
+        counters.add_bytes(1024);
+        cluster.keep_alive().await.unwrap();
+
+        let snapshot = counters.snapshot();
+        assert_eq!((snapshot.hits, snapshot.bytes), (0, 0));
+        let state = center.state.lock().unwrap();
+        let (event, payload) = &state.events[0];
+        assert_eq!(event, "keep-alive");
+        assert_eq!(payload["hits"], 2);
+        assert_eq!(payload["bytes"], 1024);
+    }
+
+    #[tokio::test]
+    async fn rejected_keep_alive_keeps_counters() {
+        let (center, cluster, counters) = start().await;
+        center.fail("keep-alive", "too many requests");
+        counters.add_hit();
+        counters.add_bytes(1024);
+
+        let err = cluster.keep_alive().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Center replied with error: too many requests"
+        );
+        let snapshot = counters.snapshot();
+        assert_eq!((snapshot.hits, snapshot.bytes), (1, 1024));
+    }
+
+    #[tokio::test]
+    async fn request_cert_returns_cert_and_key() {
+        let (_, cluster, _) = start().await;
+        let cert_key_pair = cluster.request_cert().await.unwrap();
+
+        assert_eq!(cert_key_pair.cert, "cert");
+        assert_eq!(cert_key_pair.key, "key");
+    }
+
+    #[tokio::test]
+    async fn disable_is_acked() {
+        let (center, cluster, _) = start().await;
+        cluster.disable().await.unwrap();
+
+        assert_eq!(center.count("disable"), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_enables_again_on_close() {
+        let (center, cluster, _) = start().await;
+        cluster.enable().await.unwrap();
+        let keep_alive = cluster.clone().start_keep_alive();
+
+        // A Socket.IO disconnect from the center fires `close`.
+        let socket = center.state.lock().unwrap().sockets[0].clone();
+        socket.send("41".into()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while center.count("enable") < 2 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        keep_alive.abort();
+
+        // The new connection authenticates with a refreshed token.
+        let state = center.state.lock().unwrap();
+        assert_eq!(state.connections, ["token-1", "token-2"]);
+    }
+
+    #[test]
+    fn parse_ack_unwraps_ack_array() {
+        let ack = parse_ack(Payload::Text(vec![json!([null, {"cert": "c"}])])).unwrap();
+        assert_eq!(ack, json!({"cert": "c"}));
+    }
+
+    #[test]
+    fn parse_ack_accepts_flat_args() {
+        let ack = parse_ack(Payload::Text(vec![Value::Null, json!(true)])).unwrap();
+        assert_eq!(ack, json!(true));
+    }
+
+    #[test]
+    fn parse_ack_defaults_missing_ack_to_null() {
+        let ack = parse_ack(Payload::Text(vec![json!([null])])).unwrap();
+        assert_eq!(ack, Value::Null);
+    }
+
+    #[test]
+    fn parse_ack_reports_error_message() {
+        let err = parse_ack(Payload::Text(vec![json!([{"message": "denied"}])])).unwrap_err();
+        assert_eq!(err.to_string(), "Center replied with error: denied");
+    }
+
+    #[test]
+    fn parse_ack_reports_error_without_message() {
+        let err = parse_ack(Payload::Text(vec![json!(["boom", null])])).unwrap_err();
+        assert_eq!(err.to_string(), "Center replied with error: \"boom\"");
+    }
+
+    #[test]
+    fn parse_ack_rejects_binary_payload() {
+        assert!(parse_ack(Payload::Binary(Bytes::from_static(b"ack"))).is_err());
+    }
+}
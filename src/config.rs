@@ -6,25 +6,115 @@ use anyhow::Result;
 use serde::Deserialize;
 use toml;
 
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMode {
+    Exists,
+    #[default]
+    Size,
+    Hash,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct LocalStorageConfig {
     pub cache_dir: String,
+    #[serde(default)]
+    pub check_mode: CheckMode,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServeMode {
+    /// Redirect players to the storage
+    #[default]
+    Redirect,
+    /// Stream files from the storage through this node
+    Proxy,
 }
 
 fn dav_basepath_default() -> String {
     "/dav".into()
 }
 
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum RedirectSign {
+    #[serde(rename = "alist")]
+    Alist {
+        token: String,
+        #[serde(default)]
+        expires: u64,
+    },
+}
+
 #[derive(Clone, Deserialize)]
 pub struct WebdavStorageConfig {
     pub endpoint: String,
     #[serde(default = "dav_basepath_default")]
     pub dav_basepath: String,
     pub download_basepath: String,
-    // TODO: Redirect measure requests
+    /// Public URL that `download_basepath` is reachable at, used for redirects
+    pub public_download_url: Option<String>,
+    pub redirect_sign: Option<RedirectSign>,
+    /// Dir under `download_basepath` with measure files named by size in MB
     pub measure_basepath: Option<String>,
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub check_mode: CheckMode,
+    #[serde(default)]
+    pub serve_mode: ServeMode,
+}
+
+fn s3_region_default() -> String {
+    "us-east-1".into()
+}
+
+fn presign_expires_default() -> u64 {
+    600
+}
+
+#[derive(Clone, Deserialize)]
+pub struct S3StorageConfig {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    /// Custom endpoint for S3-compatible services, e.g. MinIO
+    pub endpoint: Option<String>,
+    #[serde(default = "s3_region_default")]
+    pub region: String,
+    /// Use `endpoint/bucket/key` instead of `bucket.endpoint/key`
+    #[serde(default)]
+    pub path_style: bool,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Seconds presigned download URLs stay valid
+    #[serde(default = "presign_expires_default")]
+    pub presign_expires: u64,
+    #[serde(default)]
+    pub check_mode: CheckMode,
+}
+
+fn alist_basepath_default() -> String {
+    "/openbmclapi".into()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AlistStorageConfig {
+    pub endpoint: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "alist_basepath_default")]
+    pub basepath: String,
+    /// Alist's sign token, required when signing is enabled
+    pub token: Option<String>,
+    /// Seconds signed links stay valid, 0 for never
+    #[serde(default)]
+    pub sign_expires: u64,
+    /// Public URL of Alist when `endpoint` isn't reachable by players
+    pub public_url: Option<String>,
+    #[serde(default)]
+    pub check_mode: CheckMode,
 }
 
 #[derive(Clone, Deserialize)]
@@ -34,6 +124,10 @@ pub enum StorageType {
     Local(LocalStorageConfig),
     #[serde(rename = "webdav")]
     Webdav(WebdavStorageConfig),
+    #[serde(rename = "s3")]
+    S3(S3StorageConfig),
+    #[serde(rename = "alist")]
+    Alist(AlistStorageConfig),
 }
 
 impl Display for StorageType {
@@ -41,20 +135,57 @@ impl Display for StorageType {
         match self {
             Self::Local(_) => write!(f, "local"),
             Self::Webdav(_) => write!(f, "webdav"),
+            Self::S3(_) => write!(f, "s3"),
+            Self::Alist(_) => write!(f, "alist"),
         }
     }
 }
 
+#[derive(Clone, Deserialize)]
+pub struct ByocConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
 fn bmclapi_default() -> String {
     "https://openbmclapi.bangbang93.com".into()
 }
 
+fn port_default() -> u16 {
+    4000
+}
+
+fn sync_concurrency_default() -> usize {
+    10
+}
+
+fn sync_interval_default() -> u64 {
+    10
+}
+
+fn shutdown_timeout_default() -> u64 {
+    30
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(default = "bmclapi_default")]
     pub bmclapi: String,
     pub cluster_id: String,
     pub cluster_secret: String,
+    pub host: Option<String>,
+    #[serde(default = "port_default")]
+    pub port: u16,
+    pub public_port: Option<u16>,
+    #[serde(default = "sync_concurrency_default")]
+    pub sync_concurrency: usize,
+    /// Minutes between checks for new files on the center
+    #[serde(default = "sync_interval_default")]
+    pub sync_interval: u64,
+    /// Seconds to wait for in-flight requests when shutting down
+    #[serde(default = "shutdown_timeout_default")]
+    pub shutdown_timeout: u64,
+    pub byoc: Option<ByocConfig>,
     pub storage: Vec<StorageType>,
 }
 
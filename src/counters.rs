@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CountersSnapshot {
+    pub hits: u64,
+    pub bytes: u64,
+}
+
+/// Traffic served since the last keep-alive acknowledged by the center.
+#[derive(Default)]
+pub struct Counters {
+    hits: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Removes a reported snapshot, keeping whatever was served while the
+    /// keep-alive was in flight.
+    pub fn subtract(&self, snapshot: CountersSnapshot) {
+        self.hits.fetch_sub(snapshot.hits, Ordering::Relaxed);
+        self.bytes.fetch_sub(snapshot.bytes, Ordering::Relaxed);
+    }
+}
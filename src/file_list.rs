@@ -0,0 +1,250 @@
+use std::io::{self, BufReader, Read};
+
+use anyhow::{bail, Result};
+use futures_util::TryStreamExt;
+use reqwest::{Client, StatusCode};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{debug, info};
+
+use crate::storage::BMCLAPIFile;
+
+/// Most files to reserve room for at once, since block counts come from the
+/// network and a corrupt one would otherwise abort on allocation.
+const MAX_RESERVE: usize = 4096;
+
+pub async fn fetch_file_list(
+    client: &Client,
+    token: &str,
+    last_modified: Option<u64>,
+) -> Result<Vec<BMCLAPIFile>> {
+    let mut request = client.get("/openbmclapi/files").bearer_auth(token);
+    if let Some(last_modified) = last_modified {
+        request = request.query(&[("lastModified", last_modified)]);
+    }
+    let response = request.send().await?.error_for_status()?;
+    if response.status() == StatusCode::NO_CONTENT {
+        debug!("File list not modified since {:?}", last_modified);
+        return Ok(vec![]);
+    }
+
+    let stream = response.bytes_stream().map_err(io::Error::other);
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let files = tokio::task::spawn_blocking(move || decode_file_list(reader)).await??;
+    info!("Fetched file list with {} files", files.len());
+
+    Ok(files)
+}
+
+/// The `lastModified` to request the next incremental file list with.
+pub fn last_modified(files: &[BMCLAPIFile]) -> Option<u64> {
+    files.iter().map(|file| file.mtime).max()
+}
+
+/// Decodes the zstd compressed avro array of `{path, hash, size, mtime}`
+/// records one by one, so the whole list never has to be buffered.
+fn decode_file_list(reader: impl Read) -> Result<Vec<BMCLAPIFile>> {
+    let mut reader = AvroReader::new(zstd::Decoder::new(reader)?);
+    let mut files = vec![];
+    loop {
+        let count = reader.read_long()?;
+        if count == 0 {
+            break;
+        }
+        if count < 0 {
+            // A negative block count is followed by the block size in bytes.
+            reader.read_long()?;
+        }
+        let count = count.unsigned_abs() as usize;
+        files.reserve(count.min(MAX_RESERVE));
+        for _ in 0..count {
+            files.push(BMCLAPIFile {
+                path: reader.read_string()?,
+                hash: reader.read_string()?,
+                size: reader.read_long()? as usize,
+                mtime: reader.read_long()? as u64,
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+struct AvroReader<R> {
+    inner: BufReader<R>,
+}
+
+impl<R: Read> AvroReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+        }
+    }
+
+    fn read_long(&mut self) -> Result<i64> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                bail!("Invalid avro long");
+            }
+            let mut byte = [0u8];
+            self.inner.read_exact(&mut byte)?;
+            value |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_long()?;
+        if len < 0 {
+            bail!("Invalid avro string length: {len}");
+        }
+        // Read through `take` so a corrupt length fails on EOF instead of
+        // allocating it upfront.
+        let mut buf = vec![];
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len as usize {
+            bail!("Truncated avro string, expected {len} bytes");
+        }
+
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_long(buf: &mut Vec<u8>, value: i64) {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                buf.push(byte);
+                break;
+            }
+            buf.push(byte | 0x80);
+        }
+    }
+
+    fn write_string(buf: &mut Vec<u8>, value: &str) {
+        write_long(buf, value.len() as i64);
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn write_file(buf: &mut Vec<u8>, file: &BMCLAPIFile) {
+        write_string(buf, &file.path);
+        write_string(buf, &file.hash);
+        write_long(buf, file.size as i64);
+        write_long(buf, file.mtime as i64);
+    }
+
+    fn file(path: &str, hash: &str, size: usize, mtime: u64) -> BMCLAPIFile {
+        BMCLAPIFile {
+            path: path.into(),
+            hash: hash.into(),
+            size,
+            mtime,
+        }
+    }
+
+    #[test]
+    fn decodes_multiple_blocks() {
+        let long_path = format!("/{}", "a".repeat(100));
+        let files = vec![
+            file("/a", "0123456789abcdef0123456789abcdef", 0, 0),
+            file(
+                &long_path,
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                300,
+                1_700_000_000_000,
+            ),
+            file("/c", "ffffffffffffffffffffffffffffffff", 1 << 40, 1),
+        ];
+
+        let mut avro = vec![];
+        write_long(&mut avro, 2);
+        write_file(&mut avro, &files[0]);
+        write_file(&mut avro, &files[1]);
+        // A negative count is followed by the block size in bytes.
+        let mut block = vec![];
+        write_file(&mut block, &files[2]);
+        write_long(&mut avro, -1);
+        write_long(&mut avro, block.len() as i64);
+        avro.extend_from_slice(&block);
+        write_long(&mut avro, 0);
+
+        let compressed = zstd::encode_all(avro.as_slice(), 0).unwrap();
+        let decoded = decode_file_list(compressed.as_slice()).unwrap();
+
+        assert_eq!(decoded.len(), files.len());
+        for (decoded, expected) in decoded.iter().zip(&files) {
+            assert_eq!(decoded.path, expected.path);
+            assert_eq!(decoded.hash, expected.hash);
+            assert_eq!(decoded.size, expected.size);
+            assert_eq!(decoded.mtime, expected.mtime);
+        }
+    }
+
+    #[test]
+    fn decodes_empty_list() {
+        let compressed = zstd::encode_all([0u8].as_slice(), 0).unwrap();
+        assert!(decode_file_list(compressed.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_multi_byte_varints() {
+        let bytes = [
+            0x01, // -1
+            0x80, 0x01, // 64
+            0xd8, 0x04, // 300
+            0xff, 0xff, 0xff, 0xff, 0x0f, // i32::MIN
+        ];
+        let mut reader = AvroReader::new(bytes.as_slice());
+        assert_eq!(reader.read_long().unwrap(), -1);
+        assert_eq!(reader.read_long().unwrap(), 64);
+        assert_eq!(reader.read_long().unwrap(), 300);
+        assert_eq!(reader.read_long().unwrap(), i32::MIN as i64);
+    }
+
+    #[test]
+    fn rejects_overlong_varint() {
+        let bytes = [0xff; 11];
+        assert!(AvroReader::new(bytes.as_slice()).read_long().is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_list() {
+        let mut avro = vec![];
+        write_long(&mut avro, 1);
+        write_string(&mut avro, "/a");
+        let compressed = zstd::encode_all(avro.as_slice(), 0).unwrap();
+        assert!(decode_file_list(compressed.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_block_count() {
+        let mut avro = vec![];
+        write_long(&mut avro, 1 << 62);
+        write_file(
+            &mut avro,
+            &file("/a", "0123456789abcdef0123456789abcdef", 1, 1),
+        );
+        let compressed = zstd::encode_all(avro.as_slice(), 0).unwrap();
+        assert!(decode_file_list(compressed.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_string_length() {
+        let mut avro = vec![];
+        write_long(&mut avro, i64::MAX);
+        avro.extend_from_slice(b"/a");
+        assert!(AvroReader::new(avro.as_slice()).read_string().is_err());
+    }
+}
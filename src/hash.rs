@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::pin::pin;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+}
+
+/// Streaming verifier for the hashes the center identifies files by, which
+/// are MD5 for 32 char hashes and SHA-1 for 40 char ones.
+pub struct HashVerifier {
+    hasher: Hasher,
+    expected: String,
+}
+
+impl HashVerifier {
+    pub fn new(expected: &str) -> Result<Self> {
+        let hasher = match expected.len() {
+            32 => Hasher::Md5(Md5::new()),
+            40 => Hasher::Sha1(Sha1::new()),
+            len => bail!("Unsupported hash length {len}: {expected}"),
+        };
+
+        Ok(Self {
+            hasher,
+            expected: expected.to_ascii_lowercase(),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self.hasher {
+            Hasher::Md5(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Sha1(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+
+    pub fn verify(self) -> Result<()> {
+        let expected = self.expected.clone();
+        let actual = self.finalize();
+        if actual != expected {
+            bail!("Hash mismatch: expected {expected}, got {actual}");
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn verify_stream<E>(
+    stream: impl Stream<Item = Result<Bytes, E>>,
+    expected: &str,
+) -> Result<()>
+where
+    E: Error + Send + Sync + 'static,
+{
+    let mut stream = pin!(stream);
+    let mut verifier = HashVerifier::new(expected)?;
+    while let Some(chunk) = stream.next().await {
+        verifier.update(&chunk?);
+    }
+    verifier.verify()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_util::stream;
+
+    use super::*;
+
+    const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+    const HELLO_WORLD_SHA1: &str = "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed";
+
+    #[test]
+    fn verifies_md5() {
+        let mut verifier = HashVerifier::new(HELLO_MD5).unwrap();
+        verifier.update(b"hello");
+        verifier.verify().unwrap();
+    }
+
+    #[test]
+    fn verifies_sha1_in_chunks() {
+        let mut verifier = HashVerifier::new(HELLO_WORLD_SHA1).unwrap();
+        verifier.update(b"hello ");
+        verifier.update(b"world");
+        verifier.verify().unwrap();
+    }
+
+    #[test]
+    fn accepts_uppercase_expected_hash() {
+        let mut verifier = HashVerifier::new(&HELLO_MD5.to_uppercase()).unwrap();
+        verifier.update(b"hello");
+        verifier.verify().unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatch() {
+        let mut verifier = HashVerifier::new(HELLO_MD5).unwrap();
+        verifier.update(b"hell0");
+        assert!(verifier.verify().is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_length() {
+        let err = HashVerifier::new("abcdef").err().unwrap();
+        assert_eq!(err.to_string(), "Unsupported hash length 6: abcdef");
+    }
+
+    #[tokio::test]
+    async fn verifies_stream() {
+        let chunks = stream::iter([
+            Ok::<_, io::Error>(Bytes::from_static(b"hello ")),
+            Ok(Bytes::from_static(b"world")),
+        ]);
+        verify_stream(chunks, HELLO_WORLD_SHA1).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_stream_on_error() {
+        let chunks = stream::iter([
+            Ok(Bytes::from_static(b"hello")),
+            Err(io::Error::other("reset")),
+        ]);
+        assert!(verify_stream(chunks, HELLO_MD5).await.is_err());
+    }
+}
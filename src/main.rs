@@ -1,7 +1,17 @@
 mod bootstrap;
 mod cli;
+mod cluster;
 mod config;
+mod counters;
+mod file_list;
+mod hash;
+mod server;
+mod sign;
 mod storage;
+mod sync;
+#[cfg(test)]
+mod test_server;
+mod tls;
 mod token;
 mod utils;
 
@@ -10,18 +20,12 @@ use bootstrap::bootstrap;
 use cli::parse_cli;
 use config::load_config;
 use const_format::concatcp;
-use salvo::prelude::*;
 use tracing::error;
 
 pub const VERSION: &'static str = include_str!(concat!(env!("OUT_DIR"), "/VERSION"));
 pub const PKG_VERSION: &'static str = include_str!(concat!(env!("OUT_DIR"), "/PKG_VERSION"));
 pub const USER_AGENT: &'static str = concatcp!("openbmclapi-cluster/", PKG_VERSION);
 
-#[handler]
-async fn hello() -> &'static str {
-    "Hello World"
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -37,20 +41,10 @@ async fn main() -> Result<()> {
         }
     };
 
-    bootstrap(&config).await;
-
-    // let mut alist_storage = get_storage(config.storage[0].clone());
-
-    // let res = alist_storage
-    //     .check_missing_files(vec![BMCLAPIFile {
-    //         path: "".into(),
-    //         hash: "114".into(),
-    //         size: 1,
-    //         mtime: 0,
-    //     }])
-    //     .await?;
-
-    // println!("{:?}", res);
+    if let Err(err) = bootstrap(&config).await {
+        error!("Failed to bootstrap: {}", err);
+        return Err(err);
+    }
 
     Ok(())
 }
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use salvo::http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use salvo::http::HeaderValue;
+use salvo::prelude::*;
+use salvo::server::ServerHandle;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::StreamExt;
+use tracing::{error, info};
+
+use crate::counters::Counters;
+use crate::sign::check_sign;
+use crate::storage::{FileResponse, Storage};
+use crate::tls::CertKeyPair;
+
+const MAX_MEASURE_SIZE: u32 = 200;
+
+struct DownloadHandler {
+    storage: Arc<dyn Storage>,
+    cluster_secret: String,
+    counters: Arc<Counters>,
+}
+
+impl DownloadHandler {
+    /// Counts bytes as they are handed to the connection rather than the
+    /// advertised size, and the file size for redirects.
+    fn count(&self, file_response: FileResponse) -> FileResponse {
+        match file_response {
+            FileResponse::Stream {
+                body,
+                size,
+                content_range,
+            } => {
+                self.counters.add_hit();
+                let counters = self.counters.clone();
+                let body = body.inspect_ok(move |chunk| counters.add_bytes(chunk.len() as u64));
+
+                FileResponse::Stream {
+                    body: Box::pin(body),
+                    size,
+                    content_range,
+                }
+            }
+            FileResponse::Redirect { url, size } => {
+                self.counters.add_hit();
+                self.counters.add_bytes(size);
+
+                FileResponse::Redirect { url, size }
+            }
+            file_response => file_response,
+        }
+    }
+}
+
+#[handler]
+impl DownloadHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let hash = req
+            .param::<String>("hash")
+            .unwrap_or_default()
+            .to_lowercase();
+        let sign = req.query::<String>("s");
+        let expires = req.query::<String>("e");
+        let valid_hash = hash.len() >= 2 && hash.chars().all(|c| c.is_ascii_hexdigit());
+        if !valid_hash
+            || !check_sign(
+                &hash,
+                &self.cluster_secret,
+                sign.as_deref(),
+                expires.as_deref(),
+            )
+        {
+            res.status_code(StatusCode::FORBIDDEN);
+            res.render("Invalid signature");
+            return;
+        }
+
+        let range = req
+            .headers()
+            .get(RANGE)
+            .and_then(|range| range.to_str().ok());
+        match self.storage.serve(&hash, range).await {
+            Ok(file_response) => {
+                let headers = res.headers_mut();
+                headers.insert("x-bmclapi-hash", HeaderValue::from_str(&hash).unwrap());
+                headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=2592000"));
+                render_file_response(res, self.count(file_response));
+            }
+            Err(err) => {
+                error!("Failed to serve {}: {}", hash, err);
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+}
+
+struct MeasureHandler {
+    storage: Arc<dyn Storage>,
+    cluster_secret: String,
+}
+
+#[handler]
+impl MeasureHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let size = req.param::<u32>("size");
+        let sign = req.query::<String>("s");
+        let expires = req.query::<String>("e");
+        let Some(size) = size else {
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        };
+        if !check_sign(
+            &format!("/measure/{size}"),
+            &self.cluster_secret,
+            sign.as_deref(),
+            expires.as_deref(),
+        ) {
+            res.status_code(StatusCode::FORBIDDEN);
+            res.render("Invalid signature");
+            return;
+        }
+        if size > MAX_MEASURE_SIZE {
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        }
+
+        match self.storage.measure(size).await {
+            Ok(file_response) => render_file_response(res, file_response),
+            Err(err) => {
+                error!("Failed to measure {}MB: {}", size, err);
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+}
+
+fn render_file_response(res: &mut Response, file_response: FileResponse) {
+    match file_response {
+        FileResponse::Stream {
+            body,
+            size,
+            content_range,
+        } => {
+            if let Some(size) = size {
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(size));
+            }
+            if let Some(content_range) =
+                content_range.and_then(|content_range| HeaderValue::from_str(&content_range).ok())
+            {
+                res.status_code(StatusCode::PARTIAL_CONTENT);
+                res.headers_mut().insert(CONTENT_RANGE, content_range);
+            }
+            res.stream(body);
+        }
+        FileResponse::Redirect { url, .. } => res.render(Redirect::found(url)),
+        FileResponse::RangeNotSatisfiable { content_range } => {
+            res.status_code(StatusCode::RANGE_NOT_SATISFIABLE);
+            if let Some(content_range) =
+                content_range.and_then(|content_range| HeaderValue::from_str(&content_range).ok())
+            {
+                res.headers_mut().insert(CONTENT_RANGE, content_range);
+            }
+        }
+        FileResponse::NotFound => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+    }
+}
+
+pub fn router(storage: Arc<dyn Storage>, cluster_secret: &str, counters: Arc<Counters>) -> Router {
+    Router::new()
+        .push(Router::with_path("download/<hash>").get(DownloadHandler {
+            storage: storage.clone(),
+            cluster_secret: cluster_secret.to_string(),
+            counters,
+        }))
+        .push(Router::with_path("measure/<size>").get(MeasureHandler {
+            storage,
+            cluster_secret: cluster_secret.to_string(),
+        }))
+}
+
+pub struct RunningServer {
+    handle: ServerHandle,
+    task: JoinHandle<()>,
+}
+
+impl RunningServer {
+    /// Stops accepting new connections and lets in-flight requests finish
+    /// until `timeout` elapses.
+    pub async fn shutdown(self, timeout: Duration) {
+        info!("Draining connections for up to {:?}", timeout);
+        self.handle.stop_graceful(Some(timeout));
+        if let Err(err) = self.task.await {
+            error!("Server task failed: {}", err);
+        }
+        info!("Server stopped");
+    }
+}
+
+pub async fn start_server(
+    port: u16,
+    router: Router,
+    cert_rx: watch::Receiver<CertKeyPair>,
+) -> Result<RunningServer> {
+    let certs = WatchStream::new(cert_rx).map(|cert| cert.rustls_config());
+    let acceptor = TcpListener::new(("0.0.0.0", port))
+        .rustls(certs)
+        .try_bind()
+        .await?;
+    info!("Listening on port {port} with TLS");
+
+    let server = Server::new(acceptor);
+    let handle = server.handle();
+    let task = tokio::spawn(server.serve(router));
+
+    Ok(RunningServer { handle, task })
+}
@@ -1,11 +1,26 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+
 use anyhow::Result;
-use tracing::info;
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
 
-use crate::config::StorageType;
+use crate::config::{CheckMode, StorageType};
 
+mod alist;
 mod local;
+mod multi;
+mod s3;
+#[cfg(test)]
+mod testing;
 mod webdav;
 
+pub use multi::MultiStorage;
+
 #[derive(Clone, Debug)]
 pub struct BMCLAPIFile {
     pub path: String,
@@ -14,17 +29,193 @@ pub struct BMCLAPIFile {
     pub mtime: u64,
 }
 
+pub enum FileResponse {
+    Stream {
+        body: BoxStream<'static, io::Result<Bytes>>,
+        size: Option<u64>,
+        /// `Content-Range` of a partial response
+        content_range: Option<String>,
+    },
+    Redirect {
+        url: String,
+        size: u64,
+    },
+    /// The requested range is outside the file, with the `Content-Range`
+    /// reporting its size.
+    RangeNotSatisfiable {
+        content_range: Option<String>,
+    },
+    NotFound,
+}
+
+const CHECK_CONCURRENCY: usize = 16;
+
+impl CheckMode {
+    /// Whether a stored copy of `file` with `size` bytes counts as present.
+    /// `verify` hashes the stored copy and is only called in `Hash` mode.
+    pub async fn is_present<F>(
+        self,
+        size: u64,
+        file: &BMCLAPIFile,
+        verify: impl FnOnce() -> F,
+    ) -> bool
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let size_matches = size == file.size as u64;
+        match self {
+            CheckMode::Exists => true,
+            CheckMode::Size => size_matches,
+            CheckMode::Hash => {
+                if !size_matches {
+                    return false;
+                }
+                let result = verify().await;
+                if let Err(err) = &result {
+                    warn!("{} failed verification: {}", file.hash, err);
+                }
+                result.is_ok()
+            }
+        }
+    }
+}
+
+/// Checks `files` against the `stored` sizes by hash concurrently, returning
+/// the present files with their stored size and the missing ones.
+pub async fn find_missing<F, Fut>(
+    files: Vec<BMCLAPIFile>,
+    stored: &HashMap<String, u64>,
+    check_mode: CheckMode,
+    verify: F,
+) -> (Vec<(BMCLAPIFile, u64)>, Vec<BMCLAPIFile>)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let results: Vec<(BMCLAPIFile, Option<u64>)> = stream::iter(files)
+        .map(|file| {
+            let size = stored.get(&file.hash).copied();
+            let verify = &verify;
+            async move {
+                let present = match size {
+                    Some(size) => {
+                        check_mode
+                            .is_present(size, &file, || verify(file.hash.clone()))
+                            .await
+                    }
+                    None => false,
+                };
+                (file, size.filter(|_| present))
+            }
+        })
+        .buffer_unordered(CHECK_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut present_files = vec![];
+    let mut missing_files = vec![];
+    for (file, size) in results {
+        match size {
+            Some(size) => present_files.push((file, size)),
+            None => missing_files.push(file),
+        }
+    }
+
+    (present_files, missing_files)
+}
+
+/// Sizes of the files known to be on a remote storage by hash, so serving
+/// doesn't need a round trip.
+#[derive(Default)]
+pub struct KnownFiles {
+    sizes: Mutex<HashMap<String, u64>>,
+}
+
+impl KnownFiles {
+    pub async fn get(&self, hash: &str) -> Option<u64> {
+        self.sizes.lock().await.get(hash).copied()
+    }
+
+    pub async fn insert(&self, hash: String, size: u64) {
+        self.sizes.lock().await.insert(hash, size);
+    }
+
+    pub async fn remove(&self, hashes: impl IntoIterator<Item = String>) {
+        let mut sizes = self.sizes.lock().await;
+        for hash in hashes {
+            sizes.remove(&hash);
+        }
+    }
+
+    /// Drops the `files` already known to be present, so incremental checks
+    /// only look at new ones.
+    pub async fn unknown(&self, files: Vec<BMCLAPIFile>) -> Vec<BMCLAPIFile> {
+        let sizes = self.sizes.lock().await;
+        files
+            .into_iter()
+            .filter(|file| !sizes.contains_key(&file.hash))
+            .collect()
+    }
+
+    /// Like [`find_missing`], remembering the files that are present.
+    pub async fn check_missing<F, Fut>(
+        &self,
+        files: Vec<BMCLAPIFile>,
+        stored: &HashMap<String, u64>,
+        check_mode: CheckMode,
+        verify: F,
+    ) -> Vec<BMCLAPIFile>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let (present_files, missing_files) = find_missing(files, stored, check_mode, verify).await;
+        self.sizes.lock().await.extend(
+            present_files
+                .into_iter()
+                .map(|(file, size)| (file.hash, size)),
+        );
+
+        missing_files
+    }
+}
+
+const MEASURE_CHUNK_SIZE: usize = 1024 * 1024;
+static MEASURE_CHUNK: [u8; MEASURE_CHUNK_SIZE] = [0; MEASURE_CHUNK_SIZE];
+
+/// Generates `size` MB of payload without touching the disk.
+pub fn measure_response(size: u32) -> FileResponse {
+    let body = stream::iter((0..size).map(|_| Ok(Bytes::from_static(&MEASURE_CHUNK))));
+
+    FileResponse::Stream {
+        body: body.boxed(),
+        size: Some(size as u64 * MEASURE_CHUNK_SIZE as u64),
+        content_range: None,
+    }
+}
+
 #[async_trait::async_trait]
-pub trait Storage {
+pub trait Storage: Send + Sync {
     async fn init(&self) -> Result<()> {
         Ok(())
     }
     async fn validate(&self) -> Result<()>;
-    async fn write(&mut self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()>;
+    async fn write(&self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()>;
     async fn exists(&self, path: &str) -> bool;
     async fn get_absolute_path(&self, path: &str) -> String;
     async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>>;
-    async fn cleanup_unused_files(&mut self, files: Vec<BMCLAPIFile>) -> Result<()>;
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()>;
+    /// Serves the file with `hash`, honouring the `Range` header where the
+    /// storage supports it.
+    async fn serve(&self, hash: &str, range: Option<&str>) -> Result<FileResponse>;
+    async fn measure(&self, size: u32) -> Result<FileResponse> {
+        Ok(measure_response(size))
+    }
+    /// Whether the next check needs the full file list rather than only the
+    /// files changed since the last sync.
+    fn needs_full_check(&self) -> bool {
+        false
+    }
 }
 
 pub fn get_storage(storage_type: StorageType) -> Box<dyn Storage> {
@@ -32,5 +223,7 @@ pub fn get_storage(storage_type: StorageType) -> Box<dyn Storage> {
     match storage_type {
         StorageType::Local(storage_config) => Box::new(local::LocalStorage::new(storage_config)),
         StorageType::Webdav(storage_config) => Box::new(webdav::WebdavStorage::new(storage_config)),
+        StorageType::S3(storage_config) => Box::new(s3::S3Storage::new(storage_config)),
+        StorageType::Alist(storage_config) => Box::new(alist::AlistStorage::new(storage_config)),
     }
 }
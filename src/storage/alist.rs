@@ -0,0 +1,721 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use futures_util::{stream, StreamExt};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info};
+
+use super::{BMCLAPIFile, FileResponse, KnownFiles, Storage};
+use crate::config::AlistStorageConfig;
+use crate::hash::verify_stream;
+use crate::sign::alist_sign;
+use crate::utils::{build_client, hash_to_filename};
+
+const LIST_CONCURRENCY: usize = 8;
+
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    code: u16,
+    message: String,
+    data: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct LoginData {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct ListData {
+    content: Option<Vec<AlistObject>>,
+}
+
+#[derive(Deserialize)]
+struct AlistObject {
+    name: String,
+    size: u64,
+    is_dir: bool,
+}
+
+struct AlistFile {
+    dir: String,
+    size: u64,
+}
+
+pub struct AlistStorage {
+    storage_config: AlistStorageConfig,
+    client: Client,
+    /// Login token for the REST API, not to be confused with the sign token.
+    login_token: RwLock<Option<String>>,
+    files: KnownFiles,
+}
+
+impl AlistStorage {
+    pub fn new(storage_config: AlistStorageConfig) -> Self {
+        Self {
+            client: build_client(&storage_config.endpoint),
+            storage_config,
+            login_token: RwLock::new(None),
+            files: KnownFiles::default(),
+        }
+    }
+
+    fn full_path(&self, path: &str) -> String {
+        Path::new(&self.storage_config.basepath)
+            .join(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Links to `/d/<path>`, signed with the sign token when one is set.
+    fn download_url(&self, full_path: &str) -> String {
+        let base_url = self
+            .storage_config
+            .public_url
+            .as_deref()
+            .unwrap_or(&self.storage_config.endpoint)
+            .trim_end_matches('/');
+        let url = format!("{base_url}/d{}", encode_path(full_path));
+
+        match &self.storage_config.token {
+            None => url,
+            Some(token) => {
+                let sign = alist_sign(full_path, token, self.storage_config.sign_expires);
+                format!("{url}?sign={sign}")
+            }
+        }
+    }
+
+    async fn login(&self) -> Result<String> {
+        let response: ApiResponse<LoginData> = self
+            .client
+            .post("/api/auth/login")
+            .json(&json!({
+                "username": self.storage_config.username,
+                "password": self.storage_config.password,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response.data {
+            Some(data) if response.code == 200 => Ok(data.token),
+            _ => bail!("Failed to log in to Alist: {}", response.message),
+        }
+    }
+
+    async fn login_token(&self) -> Result<String> {
+        if let Some(token) = self.login_token.read().await.as_ref() {
+            return Ok(token.clone());
+        }
+        let token = self.login().await?;
+        *self.login_token.write().await = Some(token.clone());
+        debug!("Logged in to Alist");
+
+        Ok(token)
+    }
+
+    /// Sends an authorized API request, logging in again once if the token
+    /// has expired. Alist reports errors in the body with a 200 status.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Option<T>> {
+        let mut relogged = false;
+        loop {
+            let token = self.login_token().await?;
+            let response: ApiResponse<T> = request()
+                .header("Authorization", token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            match response.code {
+                200 => return Ok(response.data),
+                401 if !relogged => {
+                    debug!("Alist token expired, logging in again");
+                    *self.login_token.write().await = None;
+                    relogged = true;
+                }
+                code => bail!("Alist API error {}: {}", code, response.message),
+            }
+        }
+    }
+
+    async fn list(&self, path: &str) -> Result<Vec<AlistObject>> {
+        let data: Option<ListData> = self
+            .call(|| {
+                self.client.post("/api/fs/list").json(&json!({
+                    "path": path,
+                    "page": 1,
+                    "per_page": 0,
+                    "refresh": false,
+                }))
+            })
+            .await?;
+
+        Ok(data.and_then(|data| data.content).unwrap_or_default())
+    }
+
+    /// Lists every `hash[0..2]` dir under the base path concurrently.
+    async fn list_files(&self) -> Result<HashMap<String, AlistFile>> {
+        let dirs: Vec<String> = self
+            .list(&self.storage_config.basepath)
+            .await?
+            .into_iter()
+            .filter(|object| object.is_dir)
+            .map(|object| self.full_path(&object.name))
+            .collect();
+        let listed: Vec<(String, Result<Vec<AlistObject>>)> = stream::iter(dirs)
+            .map(|dir| async move {
+                let objects = self.list(&dir).await;
+                (dir, objects)
+            })
+            .buffer_unordered(LIST_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut files = HashMap::new();
+        for (dir, objects) in listed {
+            for object in objects? {
+                if object.is_dir {
+                    continue;
+                }
+                files.insert(
+                    object.name,
+                    AlistFile {
+                        dir: dir.clone(),
+                        size: object.size,
+                    },
+                );
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn verify_remote(&self, hash: &str) -> Result<()> {
+        let url = self.download_url(&self.full_path(&hash_to_filename(hash)));
+        let stream = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes_stream();
+
+        verify_stream(stream, hash).await
+    }
+
+    async fn put(&self, path: &str, content: &[u8]) -> Result<()> {
+        let full_path = encode_path(&self.full_path(path));
+        self.call::<serde_json::Value>(|| {
+            self.client
+                .put("/api/fs/put")
+                .header("File-Path", &full_path)
+                .body(content.to_vec())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, dir: &str, names: Vec<String>) -> Result<()> {
+        self.call::<serde_json::Value>(|| {
+            self.client
+                .post("/api/fs/remove")
+                .json(&json!({ "dir": dir, "names": names }))
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for AlistStorage {
+    async fn init(&self) -> Result<()> {
+        if !self.exists("").await {
+            info!("Creating base path {}", self.storage_config.basepath);
+            self.call::<serde_json::Value>(|| {
+                self.client
+                    .post("/api/fs/mkdir")
+                    .json(&json!({ "path": self.storage_config.basepath }))
+            })
+            .await?;
+        }
+        info!("Init success");
+
+        Ok(())
+    }
+
+    async fn validate(&self) -> Result<()> {
+        let put_result = self.put(".check", b"").await;
+        if let Err(err) = put_result {
+            error!("Error checking storage: {}", err);
+            bail!(err);
+        }
+        let delete_result = self
+            .remove(&self.storage_config.basepath, vec![".check".into()])
+            .await;
+        if let Err(err) = delete_result {
+            error!("Failed to delete temp file: {}", err);
+            bail!(err);
+        }
+        info!("Validate success");
+
+        Ok(())
+    }
+
+    async fn write(&self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
+        self.put(path, content).await?;
+        self.files.insert(file.hash, content.len() as u64).await;
+
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> bool {
+        let full_path = self.full_path(path);
+        self.call::<serde_json::Value>(|| {
+            self.client
+                .post("/api/fs/get")
+                .json(&json!({ "path": full_path }))
+        })
+        .await
+        .is_ok()
+    }
+
+    async fn get_absolute_path(&self, path: &str) -> String {
+        self.download_url(&self.full_path(path))
+    }
+
+    async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>> {
+        let stored: HashMap<String, u64> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|(hash, alist_file)| (hash, alist_file.size))
+            .collect();
+        let missing_files = self
+            .files
+            .check_missing(
+                files,
+                &stored,
+                self.storage_config.check_mode,
+                |hash| async move { self.verify_remote(&hash).await },
+            )
+            .await;
+        debug!("{} files missing from Alist", missing_files.len());
+
+        Ok(missing_files)
+    }
+
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
+        let remote_file_hashes: HashSet<String> = files.into_iter().map(|file| file.hash).collect();
+        let mut unused_files: HashMap<String, Vec<String>> = HashMap::new();
+        for (hash, alist_file) in self.list_files().await? {
+            if !remote_file_hashes.contains(&hash) {
+                unused_files.entry(alist_file.dir).or_default().push(hash);
+            }
+        }
+        info!(
+            "Deleting {} unused files",
+            unused_files.values().map(Vec::len).sum::<usize>()
+        );
+
+        for (dir, hashes) in unused_files {
+            if let Err(err) = self.remove(&dir, hashes.clone()).await {
+                error!("Failed to delete file: {}", err);
+                bail!(err);
+            }
+            self.files.remove(hashes).await;
+        }
+
+        Ok(())
+    }
+
+    async fn serve(&self, hash: &str, _range: Option<&str>) -> Result<FileResponse> {
+        let Some(size) = self.files.get(hash).await else {
+            return Ok(FileResponse::NotFound);
+        };
+
+        Ok(FileResponse::Redirect {
+            url: self.get_absolute_path(&hash_to_filename(hash)).await,
+            size,
+        })
+    }
+}
+
+/// Percent-encodes `path` the way Alist expects in `/d` links and the
+/// `File-Path` header.
+fn encode_path(path: &str) -> String {
+    let mut url = Url::parse("http://localhost").unwrap();
+    url.set_path(path);
+
+    url.path().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::{Arc, Mutex};
+
+    use salvo::prelude::*;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::CheckMode;
+    use crate::storage::testing::{file, hashes, start_mock, write};
+
+    const USERNAME: &str = "admin";
+    const PASSWORD: &str = "password";
+    const SIGN_TOKEN: &str = "alist-token";
+
+    #[derive(Default)]
+    struct AlistState {
+        logins: usize,
+        token: Option<String>,
+        dirs: BTreeSet<String>,
+        files: BTreeMap<String, Vec<u8>>,
+        list_refreshes: Vec<bool>,
+    }
+
+    impl AlistState {
+        fn children(&self, dir: &str) -> Vec<Value> {
+            let prefix = format!("{}/", dir.trim_end_matches('/'));
+            let mut dirs = BTreeSet::new();
+            let mut files = vec![];
+            for dir in &self.dirs {
+                if let Some(rest) = dir.strip_prefix(&prefix) {
+                    dirs.insert(rest.split('/').next().unwrap().to_string());
+                }
+            }
+            for (path, content) in &self.files {
+                let Some(rest) = path.strip_prefix(&prefix) else {
+                    continue;
+                };
+                match rest.split_once('/') {
+                    Some((name, _)) => {
+                        dirs.insert(name.to_string());
+                    }
+                    None => files.push(json!({
+                        "name": rest,
+                        "size": content.len(),
+                        "is_dir": false,
+                    })),
+                }
+            }
+
+            dirs.into_iter()
+                .map(|name| json!({ "name": name, "size": 0, "is_dir": true }))
+                .chain(files)
+                .collect()
+        }
+
+        fn exists(&self, path: &str) -> bool {
+            let path = path.trim_end_matches('/');
+            self.files.contains_key(path)
+                || self.dirs.contains(path)
+                || self
+                    .files
+                    .keys()
+                    .any(|file| file.starts_with(&format!("{path}/")))
+        }
+    }
+
+    /// Stand-in for the parts of the Alist API the storage uses. Like Alist,
+    /// it reports errors in the body with a 200 status.
+    #[derive(Clone, Default)]
+    struct MockAlist {
+        state: Arc<Mutex<AlistState>>,
+    }
+
+    impl MockAlist {
+        fn put(&self, path: &str, content: &[u8]) {
+            let mut state = self.state.lock().unwrap();
+            state.files.insert(path.to_string(), content.to_vec());
+        }
+
+        fn paths(&self) -> Vec<String> {
+            self.state.lock().unwrap().files.keys().cloned().collect()
+        }
+    }
+
+    #[handler]
+    impl MockAlist {
+        async fn handle(&self, req: &mut Request, res: &mut Response) {
+            let path = req.uri().path().to_string();
+            if path == "/api/auth/login" {
+                let body: Value = req.parse_json().await.unwrap();
+                let mut state = self.state.lock().unwrap();
+                if body["username"] != USERNAME || body["password"] != PASSWORD {
+                    res.render(Json(
+                        json!({"code": 400, "message": "password is incorrect"}),
+                    ));
+                    return;
+                }
+                state.logins += 1;
+                let token = format!("login-token-{}", state.logins);
+                state.token = Some(token.clone());
+                res.render(Json(
+                    json!({"code": 200, "message": "success", "data": {"token": token}}),
+                ));
+                return;
+            }
+
+            let authorized = {
+                let state = self.state.lock().unwrap();
+                state.token.is_some() && req.header::<String>("Authorization") == state.token
+            };
+            if !authorized {
+                res.render(Json(
+                    json!({"code": 401, "message": "token is expired", "data": null}),
+                ));
+                return;
+            }
+
+            let data = match path.as_str() {
+                "/api/fs/put" => {
+                    let file_path: String = req.header("File-Path").unwrap();
+                    let content = req.payload().await.unwrap().to_vec();
+                    self.state.lock().unwrap().files.insert(file_path, content);
+                    Ok(Value::Null)
+                }
+                "/api/fs/list" => {
+                    let body: Value = req.parse_json().await.unwrap();
+                    let mut state = self.state.lock().unwrap();
+                    state
+                        .list_refreshes
+                        .push(body["refresh"].as_bool().unwrap());
+                    let dir = body["path"].as_str().unwrap();
+                    if state.exists(dir) {
+                        Ok(json!({ "content": state.children(dir) }))
+                    } else {
+                        Err("object not found")
+                    }
+                }
+                "/api/fs/get" => {
+                    let body: Value = req.parse_json().await.unwrap();
+                    if self
+                        .state
+                        .lock()
+                        .unwrap()
+                        .exists(body["path"].as_str().unwrap())
+                    {
+                        Ok(json!({}))
+                    } else {
+                        Err("object not found")
+                    }
+                }
+                "/api/fs/mkdir" => {
+                    let body: Value = req.parse_json().await.unwrap();
+                    let dir = body["path"].as_str().unwrap().trim_end_matches('/');
+                    self.state.lock().unwrap().dirs.insert(dir.to_string());
+                    Ok(Value::Null)
+                }
+                "/api/fs/remove" => {
+                    let body: Value = req.parse_json().await.unwrap();
+                    let dir = body["dir"].as_str().unwrap().trim_end_matches('/');
+                    let mut state = self.state.lock().unwrap();
+                    for name in body["names"].as_array().unwrap() {
+                        state
+                            .files
+                            .remove(&format!("{dir}/{}", name.as_str().unwrap()));
+                    }
+                    Ok(Value::Null)
+                }
+                _ => Err("not found"),
+            };
+            match data {
+                Ok(data) => res.render(Json(
+                    json!({"code": 200, "message": "success", "data": data}),
+                )),
+                Err(message) => {
+                    res.render(Json(json!({"code": 500, "message": message, "data": null})))
+                }
+            }
+        }
+    }
+
+    fn config(endpoint: String) -> AlistStorageConfig {
+        AlistStorageConfig {
+            endpoint,
+            username: USERNAME.into(),
+            password: PASSWORD.into(),
+            basepath: "/openbmclapi".into(),
+            token: Some(SIGN_TOKEN.into()),
+            sign_expires: 0,
+            public_url: None,
+            check_mode: CheckMode::Size,
+        }
+    }
+
+    fn path(file: &BMCLAPIFile) -> String {
+        format!("/openbmclapi/{}", hash_to_filename(&file.hash))
+    }
+
+    #[tokio::test]
+    async fn init_creates_basepath() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let storage = AlistStorage::new(config);
+        storage.init().await.unwrap();
+        storage.validate().await.unwrap();
+
+        assert!(mock.state.lock().unwrap().dirs.contains("/openbmclapi"));
+        assert!(mock.paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn writes_under_hash_prefix_dirs() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let storage = AlistStorage::new(config);
+        let stored = write(&storage, b"aaa").await;
+
+        assert_eq!(mock.paths(), [path(&stored)]);
+        assert_eq!(mock.state.lock().unwrap().files[&path(&stored)], b"aaa");
+    }
+
+    #[tokio::test]
+    async fn size_check_reports_absent_and_resized_files() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let (stored, resized, absent) = (file(b"aaa"), file(b"bb"), file(b"c"));
+        mock.put(&path(&stored), b"aaa");
+        mock.put(&path(&resized), b"bbb");
+
+        let storage = AlistStorage::new(config);
+        let missing = storage
+            .check_missing_files(vec![stored, resized.clone(), absent.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&resized, &absent]));
+    }
+
+    #[tokio::test]
+    async fn check_lists_without_refreshing() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let stored = file(b"aaa");
+        mock.put(&path(&stored), b"aaa");
+
+        let storage = AlistStorage::new(config);
+        storage.check_missing_files(vec![stored]).await.unwrap();
+        let refreshes = mock.state.lock().unwrap().list_refreshes.clone();
+        assert!(!refreshes.is_empty());
+        assert!(refreshes.iter().all(|refresh| !refresh));
+    }
+
+    #[tokio::test]
+    async fn serves_checked_files_through_signed_urls() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let stored = file(b"aaa");
+        mock.put(&path(&stored), b"aaa");
+        let endpoint = config.endpoint.clone();
+        let storage = AlistStorage::new(config);
+        storage
+            .check_missing_files(vec![stored.clone()])
+            .await
+            .unwrap();
+
+        let FileResponse::Redirect { url, size } = storage.serve(&stored.hash, None).await.unwrap()
+        else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(size, 3);
+        assert_eq!(
+            url,
+            format!(
+                "{endpoint}/d{}?sign=sD5tL9hj5StT1eR22A8ruIp5GZsLtr0ytSlmCUxYP-A=:0",
+                path(&stored)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn serving_unknown_files_is_not_found() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        // Stored but never checked, so this instance doesn't know about it.
+        let stored = file(b"aaa");
+        mock.put(&path(&stored), b"aaa");
+
+        let storage = AlistStorage::new(config);
+        assert!(matches!(
+            storage.serve(&stored.hash, None).await.unwrap(),
+            FileResponse::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn cleanup_deletes_unlisted_files() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let storage = AlistStorage::new(config);
+        let kept = write(&storage, b"aaa").await;
+        let unused = write(&storage, b"bb").await;
+
+        storage
+            .cleanup_unused_files(vec![kept.clone()])
+            .await
+            .unwrap();
+        assert_eq!(mock.paths(), [path(&kept)]);
+        assert!(matches!(
+            storage.serve(&unused.hash, None).await.unwrap(),
+            FileResponse::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_public_url_without_token() {
+        let (_, mut config): (MockAlist, _) = start_mock(config).await;
+        config.token = None;
+        config.public_url = Some("https://alist.example.com/".into());
+        let storage = AlistStorage::new(config);
+        let stored = write(&storage, b"aaa").await;
+
+        let FileResponse::Redirect { url, .. } = storage.serve(&stored.hash, None).await.unwrap()
+        else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(url, format!("https://alist.example.com/d{}", path(&stored)));
+    }
+
+    #[tokio::test]
+    async fn logs_in_again_when_token_expires() {
+        let (mock, config): (MockAlist, _) = start_mock(config).await;
+        let storage = AlistStorage::new(config);
+        storage.init().await.unwrap();
+        assert_eq!(mock.state.lock().unwrap().logins, 1);
+
+        mock.state.lock().unwrap().token = Some("rotated".into());
+        assert!(storage.exists("").await);
+        assert_eq!(mock.state.lock().unwrap().logins, 2);
+
+        // Only one retry per call, the next call reuses the new token.
+        assert!(storage.exists("").await);
+        assert_eq!(mock.state.lock().unwrap().logins, 2);
+    }
+
+    #[tokio::test]
+    async fn fails_on_wrong_credentials() {
+        let (_, mut config): (MockAlist, _) = start_mock(config).await;
+        config.password = "wrong".into();
+        let storage = AlistStorage::new(config);
+        let err = storage.list("/openbmclapi").await.err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Failed to log in to Alist: password is incorrect"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_api_errors() {
+        let (_, config): (MockAlist, _) = start_mock(config).await;
+        let storage = AlistStorage::new(config);
+        let err = storage.list("/missing").await.err().unwrap();
+        assert_eq!(err.to_string(), "Alist API error 500: object not found");
+    }
+}
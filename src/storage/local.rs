@@ -1,12 +1,26 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Result};
-use tracing::error;
+use futures_util::{stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info};
 
-use super::{BMCLAPIFile, Storage};
+use super::{find_missing, BMCLAPIFile, FileResponse, Storage};
 use crate::config::LocalStorageConfig;
+use crate::hash::verify_stream;
+use crate::utils::hash_to_filename;
+
+const DELETE_CONCURRENCY: usize = 16;
+const TEMP_SUFFIX: &str = ".tmp";
+
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+}
 
 pub struct LocalStorage {
     storage_config: LocalStorageConfig,
@@ -16,13 +30,113 @@ impl LocalStorage {
     pub fn new(storage_config: LocalStorageConfig) -> Self {
         Self { storage_config }
     }
+
+    /// Files are laid out as `hash[0..2]/hash` under the cache dir, the same
+    /// as the Node implementation, so cache dirs are interchangeable.
+    fn file_path(&self, hash: &str) -> PathBuf {
+        Path::new(&self.storage_config.cache_dir).join(hash_to_filename(hash))
+    }
+
+    /// Walks every `hash[0..2]` directory of the cache dir concurrently,
+    /// including leftover temp files.
+    async fn walk_cache_dir(&self) -> Result<Vec<(String, LocalFile)>> {
+        let mut dirs = tokio::fs::read_dir(&self.storage_config.cache_dir).await?;
+        let mut tasks = vec![];
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            tasks.push(tokio::spawn(async move {
+                let mut files = vec![];
+                let mut entries = tokio::fs::read_dir(dir.path()).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let metadata = entry.metadata().await?;
+                    if metadata.is_file() {
+                        files.push((
+                            entry.file_name().to_string_lossy().to_string(),
+                            LocalFile {
+                                path: entry.path(),
+                                size: metadata.len(),
+                            },
+                        ));
+                    }
+                }
+
+                Ok::<_, io::Error>(files)
+            }));
+        }
+
+        let mut local_files = vec![];
+        for task in tasks {
+            local_files.extend(task.await??);
+        }
+
+        Ok(local_files)
+    }
+
+    async fn list_files(&self) -> Result<HashMap<String, LocalFile>> {
+        Ok(self
+            .walk_cache_dir()
+            .await?
+            .into_iter()
+            .filter(|(name, _)| !name.ends_with(TEMP_SUFFIX))
+            .collect())
+    }
+
+    async fn verify_file(&self, hash: &str) -> Result<()> {
+        let content = tokio::fs::File::open(self.file_path(hash)).await?;
+        verify_stream(ReaderStream::new(content), hash).await
+    }
+
+    async fn write_atomically(
+        temp_path: &Path,
+        file_path: &Path,
+        content: &[u8],
+    ) -> io::Result<()> {
+        let mut file = tokio::fs::File::create(temp_path).await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(temp_path, file_path).await?;
+        // The rename is only durable once the directory entry is synced too.
+        // Directories can't be opened for syncing on Windows.
+        #[cfg(unix)]
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::File::open(parent).await?.sync_all().await?;
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
 impl Storage for LocalStorage {
+    async fn init(&self) -> Result<()> {
+        if !self.exists("").await {
+            return Ok(());
+        }
+        let temp_files: Vec<PathBuf> = self
+            .walk_cache_dir()
+            .await?
+            .into_iter()
+            .filter_map(|(name, local_file)| name.ends_with(TEMP_SUFFIX).then_some(local_file.path))
+            .collect();
+        if !temp_files.is_empty() {
+            info!("Removing {} leftover temp files", temp_files.len());
+        }
+        for temp_file in temp_files {
+            if let Err(err) = tokio::fs::remove_file(&temp_file).await {
+                error!("Failed to delete temp file: {}", err);
+                bail!(err);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn validate(&self) -> Result<()> {
         let cache_dir = Path::new(&self.storage_config.cache_dir);
-        if let Err(err) = fs::create_dir(cache_dir) {
+        if let Err(err) = fs::create_dir_all(cache_dir) {
             error!("Failed to create cache dir: {}", err);
             bail!(err);
         };
@@ -46,17 +160,20 @@ impl Storage for LocalStorage {
         Ok(())
     }
 
-    async fn write(&mut self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
-        let file_path = Path::new(&self.storage_config.cache_dir).join(path);
-        let mut file = match fs::File::create(&file_path) {
-            Ok(file) => file,
-            Err(err) => {
-                error!("Failed to create file: {}", err);
+    async fn write(&self, _path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
+        let file_path = self.file_path(&file.hash);
+        if let Some(parent) = file_path.parent() {
+            if let Err(err) = tokio::fs::create_dir_all(parent).await {
+                error!("Failed to create dir: {}", err);
                 bail!(err);
             }
-        };
-        if let Err(err) = file.write_all(content) {
+        }
+        // Write to a temp file first so a crash mid-write never leaves a
+        // truncated file at the final path.
+        let temp_path = file_path.with_file_name(format!("{}{TEMP_SUFFIX}", file.hash));
+        if let Err(err) = Self::write_atomically(&temp_path, &file_path, content).await {
             error!("Failed to write file: {}", err);
+            let _ = tokio::fs::remove_file(&temp_path).await;
             bail!(err);
         }
 
@@ -64,9 +181,9 @@ impl Storage for LocalStorage {
     }
 
     async fn exists(&self, path: &str) -> bool {
-        Path::new(&self.storage_config.cache_dir)
-            .join(path)
-            .exists()
+        tokio::fs::try_exists(Path::new(&self.storage_config.cache_dir).join(path))
+            .await
+            .unwrap_or(false)
     }
 
     async fn get_absolute_path(&self, path: &str) -> String {
@@ -77,10 +194,204 @@ impl Storage for LocalStorage {
     }
 
     async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>> {
-        unimplemented!()
+        let stored: HashMap<String, u64> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|(hash, local_file)| (hash, local_file.size))
+            .collect();
+        let (_, missing_files) = find_missing(
+            files,
+            &stored,
+            self.storage_config.check_mode,
+            |hash| async move { self.verify_file(&hash).await },
+        )
+        .await;
+        debug!("{} files missing from cache dir", missing_files.len());
+
+        Ok(missing_files)
+    }
+
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
+        let remote_file_hashes: HashSet<String> = files.into_iter().map(|file| file.hash).collect();
+        let unused_files: Vec<PathBuf> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .filter_map(|(hash, local_file)| {
+                (!remote_file_hashes.contains(&hash)).then_some(local_file.path)
+            })
+            .collect();
+        info!("Deleting {} unused files", unused_files.len());
+
+        let results: Vec<io::Result<()>> = stream::iter(unused_files)
+            .map(|path| async move {
+                debug!("Deleting {}", path.display());
+                tokio::fs::remove_file(&path).await
+            })
+            .buffer_unordered(DELETE_CONCURRENCY)
+            .collect()
+            .await;
+        for result in results {
+            if let Err(err) = result {
+                error!("Failed to delete file: {}", err);
+                bail!(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve(&self, hash: &str, _range: Option<&str>) -> Result<FileResponse> {
+        let file = match tokio::fs::File::open(self.file_path(hash)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(FileResponse::NotFound);
+            }
+            Err(err) => bail!(err),
+        };
+        let size = file.metadata().await?.len();
+
+        Ok(FileResponse::Stream {
+            body: ReaderStream::new(file).boxed(),
+            size: Some(size),
+            content_range: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_util::TryStreamExt;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::testing::{file, hashes, write};
+
+    fn storage(cache_dir: &TempDir, check_mode: CheckMode) -> LocalStorage {
+        LocalStorage::new(LocalStorageConfig {
+            cache_dir: cache_dir.path().to_string_lossy().to_string(),
+            check_mode,
+        })
+    }
+
+    #[tokio::test]
+    async fn writes_under_hash_prefix_dirs() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let file = write(&storage, b"content").await;
+
+        let path = cache_dir.path().join(&file.hash[..2]).join(&file.hash);
+        assert_eq!(fs::read(path).unwrap(), b"content");
+        let entries = fs::read_dir(cache_dir.path().join(&file.hash[..2])).unwrap();
+        assert_eq!(entries.count(), 1);
     }
 
-    async fn cleanup_unused_files(&mut self, files: Vec<BMCLAPIFile>) -> Result<()> {
-        unimplemented!()
+    #[tokio::test]
+    async fn size_mode_reports_absent_and_resized_files() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let kept = write(&storage, b"kept").await;
+        let resized = write(&storage, b"resized").await;
+        fs::write(storage.file_path(&resized.hash), b"resized!").unwrap();
+        let absent = file(b"absent");
+
+        let missing = storage
+            .check_missing_files(vec![kept, resized.clone(), absent.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&resized, &absent]));
+    }
+
+    #[tokio::test]
+    async fn size_mode_misses_same_size_corruption() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let corrupted = write(&storage, b"corrupted").await;
+        fs::write(storage.file_path(&corrupted.hash), b"CORRUPTED").unwrap();
+
+        let missing = storage.check_missing_files(vec![corrupted]).await.unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hash_mode_reports_same_size_corruption() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Hash);
+        let kept = write(&storage, b"kept").await;
+        let corrupted = write(&storage, b"corrupted").await;
+        fs::write(storage.file_path(&corrupted.hash), b"CORRUPTED").unwrap();
+
+        let missing = storage
+            .check_missing_files(vec![kept, corrupted.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&corrupted]));
+    }
+
+    #[tokio::test]
+    async fn exists_mode_ignores_size() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Exists);
+        let resized = write(&storage, b"resized").await;
+        fs::write(storage.file_path(&resized.hash), b"").unwrap();
+        let absent = file(b"absent");
+
+        let missing = storage
+            .check_missing_files(vec![resized, absent.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&absent]));
+    }
+
+    #[tokio::test]
+    async fn cleanup_deletes_unlisted_files() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let kept = write(&storage, b"kept").await;
+        let unused = write(&storage, b"unused").await;
+
+        storage
+            .cleanup_unused_files(vec![kept.clone()])
+            .await
+            .unwrap();
+        assert!(storage.file_path(&kept.hash).exists());
+        assert!(!storage.file_path(&unused.hash).exists());
+    }
+
+    #[tokio::test]
+    async fn init_removes_leftover_temp_files() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let kept = write(&storage, b"kept").await;
+        let temp_path = storage
+            .file_path(&kept.hash)
+            .with_file_name(format!("{}{TEMP_SUFFIX}", kept.hash));
+        fs::write(&temp_path, b"partial").unwrap();
+
+        storage.init().await.unwrap();
+        assert!(!temp_path.exists());
+        assert!(storage.file_path(&kept.hash).exists());
+    }
+
+    #[tokio::test]
+    async fn serves_stored_files() {
+        let cache_dir = TempDir::new().unwrap();
+        let storage = storage(&cache_dir, CheckMode::Size);
+        let stored = write(&storage, b"content").await;
+
+        let FileResponse::Stream { body, size, .. } =
+            storage.serve(&stored.hash, None).await.unwrap()
+        else {
+            panic!("expected a stream");
+        };
+        assert_eq!(size, Some(7));
+        let chunks: Vec<Bytes> = body.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"content");
+        assert!(matches!(
+            storage.serve(&file(b"absent").hash, None).await.unwrap(),
+            FileResponse::NotFound
+        ));
     }
 }
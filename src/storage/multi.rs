@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use futures_util::future;
+use tracing::{error, info, warn};
+
+use super::{get_storage, BMCLAPIFile, FileResponse, Storage};
+use crate::config::StorageType;
+
+/// How long a backend that failed to serve is skipped for.
+const UNHEALTHY_DURATION: Duration = Duration::from_secs(60);
+
+struct Backend {
+    name: String,
+    storage: Box<dyn Storage>,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_until
+            .lock()
+            .unwrap()
+            .map_or(true, |until| Instant::now() >= until)
+    }
+
+    fn mark_unhealthy(&self) {
+        *self.unhealthy_until.lock().unwrap() = Some(Instant::now() + UNHEALTHY_DURATION);
+    }
+
+    fn mark_healthy(&self) {
+        *self.unhealthy_until.lock().unwrap() = None;
+    }
+}
+
+/// Keeps every configured storage in sync and serves from the first healthy
+/// one, failing over to the next when a backend errors.
+pub struct MultiStorage {
+    backends: Vec<Backend>,
+    /// Backends each file was missing from at the last check, by hash, so a
+    /// sync only writes to the backends that need the file.
+    missing: Mutex<HashMap<String, Vec<usize>>>,
+    /// Backends whose last check failed, so they missed the files changed
+    /// since and have to be checked against the full list again.
+    stale: Mutex<HashSet<usize>>,
+}
+
+impl MultiStorage {
+    pub fn new(storage_types: Vec<StorageType>) -> Self {
+        let backends = storage_types
+            .into_iter()
+            .enumerate()
+            .map(|(index, storage_type)| Backend {
+                name: format!("{storage_type}#{index}"),
+                storage: get_storage(storage_type),
+                unhealthy_until: Mutex::new(None),
+            })
+            .collect();
+
+        Self {
+            backends,
+            missing: Mutex::new(HashMap::new()),
+            stale: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Healthy backends first, in config order, then the unhealthy ones as a
+    /// last resort.
+    fn serving_order(&self) -> Vec<&Backend> {
+        let (mut healthy, unhealthy): (Vec<&Backend>, Vec<&Backend>) = self
+            .backends
+            .iter()
+            .partition(|backend| backend.is_healthy());
+        healthy.extend(unhealthy);
+
+        healthy
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MultiStorage {
+    async fn init(&self) -> Result<()> {
+        for backend in &self.backends {
+            if let Err(err) = backend.storage.init().await {
+                error!("Failed to init storage {}: {}", backend.name, err);
+                bail!(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn validate(&self) -> Result<()> {
+        for backend in &self.backends {
+            if let Err(err) = backend.storage.validate().await {
+                error!("Failed to validate storage {}: {}", backend.name, err);
+                bail!(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write(&self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
+        let targets = self
+            .missing
+            .lock()
+            .unwrap()
+            .get(&file.hash)
+            .cloned()
+            .unwrap_or_else(|| (0..self.backends.len()).collect());
+        let results = future::join_all(targets.iter().map(|&index| {
+            self.backends[index]
+                .storage
+                .write(path, content, file.clone())
+        }))
+        .await;
+
+        let mut failed = vec![];
+        for (index, result) in targets.into_iter().zip(results) {
+            if let Err(err) = result {
+                warn!(
+                    "Failed to write {} to storage {}: {}",
+                    file.hash, self.backends[index].name, err
+                );
+                failed.push(index);
+            }
+        }
+        if failed.is_empty() {
+            self.missing.lock().unwrap().remove(&file.hash);
+            return Ok(());
+        }
+        let failed_count = failed.len();
+        self.missing
+            .lock()
+            .unwrap()
+            .insert(file.hash.clone(), failed);
+
+        bail!("Failed to write {} to {failed_count} storages", file.hash)
+    }
+
+    async fn exists(&self, path: &str) -> bool {
+        for backend in &self.backends {
+            if backend.storage.exists(path).await {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    async fn get_absolute_path(&self, path: &str) -> String {
+        self.serving_order()[0]
+            .storage
+            .get_absolute_path(path)
+            .await
+    }
+
+    async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>> {
+        let results = future::join_all(
+            self.backends
+                .iter()
+                .map(|backend| backend.storage.check_missing_files(files.clone())),
+        )
+        .await;
+
+        let mut missing: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut missing_files = vec![];
+        let mut last_err = None;
+        let mut checked = 0;
+        for (index, result) in results.into_iter().enumerate() {
+            // Keep syncing the other backends, this one is rechecked against the
+            // full list on the next sync.
+            let backend_missing = match result {
+                Ok(backend_missing) => {
+                    self.stale.lock().unwrap().remove(&index);
+                    backend_missing
+                }
+                Err(err) => {
+                    self.stale.lock().unwrap().insert(index);
+                    error!(
+                        "Failed to check files on storage {}, skipping it: {}",
+                        self.backends[index].name, err
+                    );
+                    self.backends[index].mark_unhealthy();
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            checked += 1;
+            info!(
+                "{} files missing from storage {}",
+                backend_missing.len(),
+                self.backends[index].name
+            );
+            for file in backend_missing {
+                let indices = missing.entry(file.hash.clone()).or_default();
+                if indices.is_empty() {
+                    missing_files.push(file);
+                }
+                indices.push(index);
+            }
+        }
+        if checked == 0 {
+            if let Some(err) = last_err {
+                bail!(err);
+            }
+        }
+        *self.missing.lock().unwrap() = missing;
+
+        Ok(missing_files)
+    }
+
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
+        for backend in &self.backends {
+            if let Err(err) = backend.storage.cleanup_unused_files(files.clone()).await {
+                error!("Failed to clean up storage {}: {}", backend.name, err);
+                bail!(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn serve(&self, hash: &str, range: Option<&str>) -> Result<FileResponse> {
+        let mut last_err = None;
+        for backend in self.serving_order() {
+            match backend.storage.serve(hash, range).await {
+                Ok(FileResponse::NotFound) => continue,
+                Ok(file_response) => {
+                    backend.mark_healthy();
+                    return Ok(file_response);
+                }
+                Err(err) => {
+                    warn!(
+                        "Storage {} failed to serve {}, failing over: {}",
+                        backend.name, hash, err
+                    );
+                    if is_backend_failure(&err) {
+                        backend.mark_unhealthy();
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(FileResponse::NotFound),
+        }
+    }
+
+    async fn measure(&self, size: u32) -> Result<FileResponse> {
+        let mut last_err = None;
+        for backend in self.serving_order() {
+            match backend.storage.measure(size).await {
+                Ok(file_response) => return Ok(file_response),
+                Err(err) => {
+                    warn!("Storage {} failed to measure: {}", backend.name, err);
+                    if is_backend_failure(&err) {
+                        backend.mark_unhealthy();
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => bail!("No storage configured"),
+        }
+    }
+
+    fn needs_full_check(&self) -> bool {
+        !self.stale.lock().unwrap().is_empty()
+    }
+}
+
+/// Whether `err` means the backend itself is failing, i.e. a transport error
+/// or a 5xx, rather than a 4xx caused by the request.
+fn is_backend_failure(err: &anyhow::Error) -> bool {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .and_then(reqwest::Error::status)
+        .map_or(true, |status| !status.is_client_error())
+}
@@ -0,0 +1,643 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info};
+
+use super::{BMCLAPIFile, FileResponse, KnownFiles, Storage};
+use crate::config::S3StorageConfig;
+use crate::hash::verify_stream;
+use crate::utils::{hash_to_filename, path_basename};
+
+/// DeleteObjects accepts at most 1000 keys per request.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+struct S3Object {
+    key: String,
+    size: u64,
+}
+
+pub struct S3Storage {
+    storage_config: S3StorageConfig,
+    client: Client,
+    files: KnownFiles,
+}
+
+impl S3Storage {
+    pub fn new(storage_config: S3StorageConfig) -> Self {
+        let credentials = Credentials::new(
+            &storage_config.access_key_id,
+            &storage_config.secret_access_key,
+            None,
+            None,
+            "config",
+        );
+        let mut config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(storage_config.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(storage_config.path_style);
+        if let Some(endpoint) = &storage_config.endpoint {
+            config = config.endpoint_url(endpoint);
+        }
+
+        Self {
+            client: Client::from_conf(config.build()),
+            storage_config,
+            files: KnownFiles::default(),
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        let prefix = self.storage_config.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            path.to_string()
+        } else {
+            format!("{prefix}/{path}")
+        }
+    }
+
+    /// Lists every object under the prefix with ListObjectsV2, by basename.
+    async fn list_objects(&self) -> Result<HashMap<String, S3Object>> {
+        let prefix = self.key("");
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.storage_config.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+        let mut objects = HashMap::new();
+        while let Some(page) = pages.next().await {
+            for object in page?.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+                let Some(basename) = path_basename(key) else {
+                    continue;
+                };
+                objects.insert(
+                    basename.to_string(),
+                    S3Object {
+                        key: key.to_string(),
+                        size: object.size().unwrap_or_default().max(0) as u64,
+                    },
+                );
+            }
+        }
+        debug!("Listed {} objects under {:?}", objects.len(), prefix);
+
+        Ok(objects)
+    }
+
+    async fn verify_object(&self, key: &str, hash: &str) -> Result<()> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.storage_config.bucket)
+            .key(key)
+            .send()
+            .await?;
+
+        verify_stream(ReaderStream::new(object.body.into_async_read()), hash).await
+    }
+
+    async fn delete_objects(&self, keys: Vec<String>) -> Result<()> {
+        for chunk in keys.chunks(DELETE_BATCH_SIZE) {
+            let objects = chunk
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = self
+                .client
+                .delete_objects()
+                .bucket(&self.storage_config.bucket)
+                .delete(Delete::builder().set_objects(Some(objects)).build()?)
+                .send()
+                .await?;
+            if let Some(err) = output.errors().first() {
+                bail!(
+                    "Failed to delete {} objects, first: {:?} {:?}",
+                    output.errors().len(),
+                    err.key(),
+                    err.message()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+    async fn init(&self) -> Result<()> {
+        if let Err(err) = self
+            .client
+            .head_bucket()
+            .bucket(&self.storage_config.bucket)
+            .send()
+            .await
+        {
+            error!(
+                "Failed to access bucket {}: {}",
+                self.storage_config.bucket, err
+            );
+            bail!(err);
+        }
+        info!("Init success");
+
+        Ok(())
+    }
+
+    async fn validate(&self) -> Result<()> {
+        let key = self.key(".check");
+        let put_result = self
+            .client
+            .put_object()
+            .bucket(&self.storage_config.bucket)
+            .key(&key)
+            .body(ByteStream::from_static(b""))
+            .send()
+            .await;
+        if let Err(err) = put_result {
+            error!("Error checking storage: {}", err);
+            bail!(err);
+        }
+        let delete_result = self
+            .client
+            .delete_object()
+            .bucket(&self.storage_config.bucket)
+            .key(&key)
+            .send()
+            .await;
+        if let Err(err) = delete_result {
+            error!("Failed to delete temp file: {}", err);
+            bail!(err);
+        }
+        info!("Validate success");
+
+        Ok(())
+    }
+
+    async fn write(&self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.storage_config.bucket)
+            .key(self.key(path))
+            .content_length(content.len() as i64)
+            .body(ByteStream::from(content.to_vec()))
+            .send()
+            .await?;
+        self.files.insert(file.hash, content.len() as u64).await;
+
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> bool {
+        self.client
+            .head_object()
+            .bucket(&self.storage_config.bucket)
+            .key(self.key(path))
+            .send()
+            .await
+            .is_ok()
+    }
+
+    async fn get_absolute_path(&self, path: &str) -> String {
+        format!("s3://{}/{}", self.storage_config.bucket, self.key(path))
+    }
+
+    async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>> {
+        let stored: HashMap<String, u64> = self
+            .list_objects()
+            .await?
+            .into_iter()
+            .map(|(hash, object)| (hash, object.size))
+            .collect();
+        let missing_files = self
+            .files
+            .check_missing(
+                files,
+                &stored,
+                self.storage_config.check_mode,
+                |hash| async move {
+                    let key = self.key(&hash_to_filename(&hash));
+                    self.verify_object(&key, &hash).await
+                },
+            )
+            .await;
+        debug!("{} files missing from bucket", missing_files.len());
+
+        Ok(missing_files)
+    }
+
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
+        let remote_file_hashes: HashSet<String> = files.into_iter().map(|file| file.hash).collect();
+        let unused_objects: Vec<(String, String)> = self
+            .list_objects()
+            .await?
+            .into_iter()
+            .filter(|(hash, _)| !remote_file_hashes.contains(hash))
+            .map(|(hash, object)| (hash, object.key))
+            .collect();
+        info!("Deleting {} unused files", unused_objects.len());
+
+        let (hashes, keys): (Vec<String>, Vec<String>) = unused_objects.into_iter().unzip();
+        if let Err(err) = self.delete_objects(keys).await {
+            error!("Failed to delete file: {}", err);
+            bail!(err);
+        }
+        self.files.remove(hashes).await;
+
+        Ok(())
+    }
+
+    async fn serve(&self, hash: &str, _range: Option<&str>) -> Result<FileResponse> {
+        let Some(size) = self.files.get(hash).await else {
+            return Ok(FileResponse::NotFound);
+        };
+        let presigned = self
+            .client
+            .get_object()
+            .bucket(&self.storage_config.bucket)
+            .key(self.key(&hash_to_filename(hash)))
+            .presigned(PresigningConfig::expires_in(Duration::from_secs(
+                self.storage_config.presign_expires,
+            ))?)
+            .await?;
+
+        Ok(FileResponse::Redirect {
+            url: presigned.uri().to_string(),
+            size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use reqwest::Url;
+    use salvo::http::header::CONTENT_ENCODING;
+    use salvo::prelude::*;
+
+    use super::*;
+    use crate::config::CheckMode;
+    use crate::storage::testing::{file, hashes, start_mock, write};
+
+    const BUCKET: &str = "bucket";
+    const LIST_PAGE_SIZE: usize = 1000;
+
+    #[derive(Default)]
+    struct S3State {
+        objects: BTreeMap<String, Vec<u8>>,
+        list_requests: usize,
+        delete_batches: Vec<usize>,
+    }
+
+    /// Path-style stand-in for the S3 operations the storage uses.
+    #[derive(Clone, Default)]
+    struct MockS3 {
+        state: Arc<Mutex<S3State>>,
+    }
+
+    impl MockS3 {
+        fn put(&self, key: &str, content: &[u8]) {
+            let mut state = self.state.lock().unwrap();
+            state.objects.insert(key.to_string(), content.to_vec());
+        }
+
+        fn keys(&self) -> Vec<String> {
+            self.state.lock().unwrap().objects.keys().cloned().collect()
+        }
+
+        fn list(&self, query: &HashMap<String, String>) -> String {
+            let mut state = self.state.lock().unwrap();
+            state.list_requests += 1;
+            let prefix = query.get("prefix").map_or("", String::as_str);
+            let start = query.get("continuation-token").map_or("", String::as_str);
+            let mut keys = state
+                .objects
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix) && key.as_str() > start);
+            let page: Vec<_> = keys.by_ref().take(LIST_PAGE_SIZE).collect();
+            let truncated = keys.next().is_some();
+
+            let mut xml = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                 <Name>{BUCKET}</Name><Prefix>{prefix}</Prefix><KeyCount>{}</KeyCount>\
+                 <MaxKeys>{LIST_PAGE_SIZE}</MaxKeys><IsTruncated>{truncated}</IsTruncated>",
+                page.len()
+            );
+            for (key, content) in &page {
+                xml += &format!(
+                    "<Contents><Key>{key}</Key><Size>{}</Size></Contents>",
+                    content.len()
+                );
+            }
+            if let (true, Some((key, _))) = (truncated, page.last()) {
+                xml += &format!("<NextContinuationToken>{key}</NextContinuationToken>");
+            }
+
+            xml + "</ListBucketResult>"
+        }
+
+        fn delete(&self, body: &str) -> String {
+            let keys: Vec<&str> = body
+                .split("<Key>")
+                .skip(1)
+                .filter_map(|part| part.split_once("</Key>"))
+                .map(|(key, _)| key)
+                .collect();
+            let mut state = self.state.lock().unwrap();
+            state.delete_batches.push(keys.len());
+            let mut xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                           <DeleteResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">"
+                .to_string();
+            for key in keys {
+                state.objects.remove(key);
+                xml += &format!("<Deleted><Key>{key}</Key></Deleted>");
+            }
+
+            xml + "</DeleteResult>"
+        }
+    }
+
+    #[handler]
+    impl MockS3 {
+        async fn handle(&self, req: &mut Request, res: &mut Response) {
+            let path = req.uri().path().trim_start_matches('/').to_string();
+            let query: HashMap<String, String> = req
+                .uri()
+                .query()
+                .map(|query| {
+                    Url::parse(&format!("http://localhost/?{query}"))
+                        .unwrap()
+                        .query_pairs()
+                        .into_owned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            let chunked = req
+                .header::<String>(CONTENT_ENCODING)
+                .is_some_and(|encoding| encoding.contains("aws-chunked"));
+            let mut body = req.payload_with_max_size(16 << 20).await.unwrap().to_vec();
+            if chunked {
+                body = decode_aws_chunked(&body);
+            }
+
+            let Some(key) = path.strip_prefix(&format!("{BUCKET}/")) else {
+                match (req.method().as_str(), path.as_str()) {
+                    ("HEAD", BUCKET) => {}
+                    ("GET", BUCKET) if query.get("list-type").is_some_and(|t| t == "2") => {
+                        res.render(Text::Xml(self.list(&query)));
+                    }
+                    ("POST", BUCKET) if query.contains_key("delete") => {
+                        res.render(Text::Xml(self.delete(&String::from_utf8(body).unwrap())));
+                    }
+                    _ => {
+                        res.status_code(StatusCode::NOT_FOUND);
+                        res.render(Text::Xml(
+                            "<Error><Code>NoSuchBucket</Code><Message>No such bucket</Message></Error>",
+                        ));
+                    }
+                }
+                return;
+            };
+
+            let mut state = self.state.lock().unwrap();
+            match req.method().as_str() {
+                "PUT" => {
+                    state.objects.insert(key.to_string(), body);
+                }
+                "DELETE" => {
+                    state.objects.remove(key);
+                    res.status_code(StatusCode::NO_CONTENT);
+                }
+                method => match state.objects.get(key) {
+                    Some(content) if method == "GET" => {
+                        res.write_body(content.clone()).unwrap();
+                    }
+                    Some(content) => {
+                        res.add_header("Content-Length", content.len(), true)
+                            .unwrap();
+                    }
+                    None => {
+                        res.status_code(StatusCode::NOT_FOUND);
+                        res.render(Text::Xml(
+                            "<Error><Code>NoSuchKey</Code><Message>No such key</Message></Error>",
+                        ));
+                    }
+                },
+            }
+        }
+    }
+
+    /// Strips the `<hex size>[;extensions]\r\n<data>\r\n` framing newer SDKs
+    /// use for uploads, dropping the trailing checksum headers.
+    fn decode_aws_chunked(mut body: &[u8]) -> Vec<u8> {
+        let mut decoded = vec![];
+        loop {
+            let line_end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let line = std::str::from_utf8(&body[..line_end]).unwrap();
+            let size = usize::from_str_radix(line.split(';').next().unwrap(), 16).unwrap();
+            if size == 0 {
+                return decoded;
+            }
+            let data = &body[line_end + 2..];
+            decoded.extend_from_slice(&data[..size]);
+            body = &data[size + 2..];
+        }
+    }
+
+    fn config(endpoint: String) -> S3StorageConfig {
+        S3StorageConfig {
+            bucket: BUCKET.into(),
+            prefix: "/openbmclapi/".into(),
+            endpoint: Some(endpoint),
+            region: "us-east-1".into(),
+            path_style: true,
+            access_key_id: "access-key".into(),
+            secret_access_key: "secret-key".into(),
+            presign_expires: 600,
+            check_mode: CheckMode::Hash,
+        }
+    }
+
+    fn key(file: &BMCLAPIFile) -> String {
+        format!("openbmclapi/{}", hash_to_filename(&file.hash))
+    }
+
+    /// Seeds `count` one-byte objects, bypassing the storage.
+    fn seed(mock: &MockS3, count: usize) -> Vec<BMCLAPIFile> {
+        (0..count)
+            .map(|i| {
+                let file = file(format!("{i}").as_bytes());
+                mock.put(&key(&file), b"x");
+                BMCLAPIFile { size: 1, ..file }
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn init_and_validate_leave_no_objects() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let storage = S3Storage::new(config);
+        storage.init().await.unwrap();
+        storage.validate().await.unwrap();
+
+        assert!(mock.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn init_fails_on_missing_bucket() {
+        let (_, mut config): (MockS3, _) = start_mock(config).await;
+        config.bucket = "missing".into();
+
+        assert!(S3Storage::new(config).init().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_under_prefix() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let storage = S3Storage::new(config);
+        let stored = write(&storage, b"content").await;
+
+        assert_eq!(mock.keys(), [key(&stored)]);
+        assert_eq!(
+            mock.state.lock().unwrap().objects[&key(&stored)],
+            b"content"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_reports_absent_files() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let stored = file(b"stored");
+        mock.put(&key(&stored), b"stored");
+        let absent = file(b"absent");
+
+        let storage = S3Storage::new(config);
+        let missing = storage
+            .check_missing_files(vec![stored, absent.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&absent]));
+    }
+
+    #[tokio::test]
+    async fn hash_check_reports_same_size_corruption() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let corrupted = file(b"corrupted");
+        mock.put(&key(&corrupted), b"CORRUPTED");
+
+        let storage = S3Storage::new(config);
+        let missing = storage
+            .check_missing_files(vec![corrupted.clone()])
+            .await
+            .unwrap();
+        assert_eq!(hashes(&missing), hashes([&corrupted]));
+    }
+
+    #[tokio::test]
+    async fn check_lists_every_page() {
+        let (mock, mut config): (MockS3, _) = start_mock(config).await;
+        config.check_mode = CheckMode::Size;
+        let files = seed(&mock, 2500);
+
+        let storage = S3Storage::new(config);
+        let missing = storage.check_missing_files(files).await.unwrap();
+        assert!(missing.is_empty());
+        assert_eq!(mock.state.lock().unwrap().list_requests, 3);
+    }
+
+    #[tokio::test]
+    async fn serves_checked_files_through_presigned_urls() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let stored = file(b"stored");
+        mock.put(&key(&stored), b"stored");
+        let endpoint = config.endpoint.clone().unwrap();
+        let storage = S3Storage::new(config);
+        storage
+            .check_missing_files(vec![stored.clone()])
+            .await
+            .unwrap();
+
+        let FileResponse::Redirect { url, size } = storage.serve(&stored.hash, None).await.unwrap()
+        else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(size, 6);
+        assert!(url.starts_with(&format!("{endpoint}/{BUCKET}/{}?", key(&stored))));
+        assert!(url.contains("X-Amz-Expires=600"));
+        assert!(url.contains("X-Amz-Signature="));
+        let body = reqwest::get(&url).await.unwrap().bytes().await.unwrap();
+        assert_eq!(&body[..], b"stored");
+    }
+
+    #[tokio::test]
+    async fn serves_written_files() {
+        let (_, config): (MockS3, _) = start_mock(config).await;
+        let storage = S3Storage::new(config);
+        let stored = write(&storage, b"content").await;
+
+        assert!(matches!(
+            storage.serve(&stored.hash, None).await.unwrap(),
+            FileResponse::Redirect { size: 7, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn serving_unknown_files_is_not_found() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        // Stored but never checked, so this instance doesn't know about it.
+        let stored = file(b"stored");
+        mock.put(&key(&stored), b"stored");
+
+        let storage = S3Storage::new(config);
+        assert!(matches!(
+            storage.serve(&stored.hash, None).await.unwrap(),
+            FileResponse::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn cleanup_deletes_unlisted_objects() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        let storage = S3Storage::new(config);
+        let kept = write(&storage, b"kept").await;
+        let unused = write(&storage, b"unused").await;
+
+        storage
+            .cleanup_unused_files(vec![kept.clone()])
+            .await
+            .unwrap();
+        assert_eq!(mock.keys(), [key(&kept)]);
+        assert!(matches!(
+            storage.serve(&unused.hash, None).await.unwrap(),
+            FileResponse::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn cleanup_deletes_in_batches_of_1000() {
+        let (mock, config): (MockS3, _) = start_mock(config).await;
+        seed(&mock, 2500);
+        // Outside the prefix, so never listed or deleted.
+        mock.put("other/file", b"x");
+
+        let storage = S3Storage::new(config);
+        storage.cleanup_unused_files(vec![]).await.unwrap();
+        assert_eq!(mock.state.lock().unwrap().delete_batches, [1000, 1000, 500]);
+        assert_eq!(mock.keys(), ["other/file"]);
+    }
+}
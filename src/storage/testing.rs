@@ -0,0 +1,49 @@
+use md5::{Digest, Md5};
+use salvo::Handler;
+
+use super::{BMCLAPIFile, Storage};
+use crate::test_server;
+use crate::utils::hash_to_filename;
+
+/// The entry the center would list for `content`, hashed with MD5.
+pub fn file(content: &[u8]) -> BMCLAPIFile {
+    let hash = hex::encode(Md5::digest(content));
+
+    BMCLAPIFile {
+        path: format!("/files/{hash}"),
+        hash,
+        size: content.len(),
+        mtime: 0,
+    }
+}
+
+/// Sorted hashes of `files`, to compare results that come back in any order.
+pub fn hashes<'a>(files: impl IntoIterator<Item = &'a BMCLAPIFile>) -> Vec<String> {
+    let mut hashes: Vec<String> = files.into_iter().map(|file| file.hash.clone()).collect();
+    hashes.sort();
+
+    hashes
+}
+
+/// Writes `content` to `storage` the way a sync does, returning its entry.
+pub async fn write(storage: &impl Storage, content: &[u8]) -> BMCLAPIFile {
+    let file = file(content);
+    storage
+        .write(&hash_to_filename(&file.hash), content, file.clone())
+        .await
+        .unwrap();
+
+    file
+}
+
+/// Serves a fresh `M` mock on a local port, returning it with the config of a
+/// storage pointed at it.
+pub async fn start_mock<M, C>(config: impl FnOnce(String) -> C) -> (M, C)
+where
+    M: Handler + Clone + Default,
+{
+    let mock = M::default();
+    let endpoint = test_server::start(mock.clone()).await;
+
+    (mock, config(endpoint))
+}
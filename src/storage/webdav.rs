@@ -1,30 +1,61 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use anyhow::{bail, Ok, Result};
-use regex::Regex;
-use reqwest_dav::list_cmd::{ListEntity, ListFile, ListFolder};
-use reqwest_dav::{Auth, Client, ClientBuilder, Depth};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::{StatusCode, Url};
+use reqwest_dav::list_cmd::{ListEntity, ListFolder};
+use reqwest_dav::{Auth, Client, ClientBuilder, DecodeError, Depth};
 use tokio::sync::Mutex;
-use tracing::{error, info, trace};
-
-use super::{BMCLAPIFile, Storage};
-use crate::config::WebdavStorageConfig;
-use crate::utils::path_basename;
+use tracing::{debug, error, info, trace};
+
+use super::{measure_response, BMCLAPIFile, FileResponse, KnownFiles, Storage};
+use crate::config::{RedirectSign, ServeMode, WebdavStorageConfig};
+use crate::hash::verify_stream;
+use crate::sign::alist_sign;
+use crate::utils::{hash_to_filename, path_basename};
+use crate::USER_AGENT;
+
+const EMPTY_FILES_NAME: &str = ".empty_files";
+
+/// Metadata of a remote file from a PROPFIND, without its body.
+struct WebdavStat {
+    href: String,
+    size: u64,
+    mtime: DateTime<Utc>,
+}
 
-struct WebdavFile {
-    path: String,
-    size: usize,
+impl From<ListEntity> for WebdavStat {
+    fn from(entity: ListEntity) -> Self {
+        match entity {
+            ListEntity::File(file) => Self {
+                href: file.href,
+                size: file.content_length.max(0) as u64,
+                mtime: file.last_modified,
+            },
+            ListEntity::Folder(folder) => Self {
+                href: folder.href,
+                size: 0,
+                mtime: folder.last_modified,
+            },
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct WebdavStorage {
     storage_config: WebdavStorageConfig,
     webdav_client: Client,
-    files: Arc<Mutex<HashMap<String, WebdavFile>>>,
-    empty_files: Vec<String>,
+    http_client: reqwest::Client,
+    files: Arc<KnownFiles>,
+    /// Hashes of zero-byte files, which are never uploaded.
+    empty_files: Arc<Mutex<HashSet<String>>>,
 }
 
 impl WebdavStorage {
@@ -37,12 +68,17 @@ impl WebdavStorage {
             ))
             .build()
             .unwrap();
+        let http_client = reqwest::ClientBuilder::new()
+            .user_agent(USER_AGENT)
+            .build()
+            .unwrap();
 
         Self {
             storage_config,
             webdav_client,
-            files: Arc::new(Mutex::new(HashMap::new())),
-            empty_files: vec![],
+            http_client,
+            files: Arc::new(KnownFiles::default()),
+            empty_files: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
@@ -53,23 +89,142 @@ impl WebdavStorage {
             .to_string()
     }
 
+    /// `empty_files` is kept on the storage itself so it survives restarts.
+    fn empty_files_path(&self) -> String {
+        Path::new(&self.download_basepath_with_dav_basepath())
+            .join(EMPTY_FILES_NAME)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    async fn load_empty_files(&self) -> Result<HashSet<String>> {
+        let response = self
+            .http_client
+            .get(self.endpoint_url(&self.empty_files_path()))
+            .basic_auth(
+                &self.storage_config.username,
+                Some(&self.storage_config.password),
+            )
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(HashSet::new());
+        }
+        let content = response.error_for_status()?.text().await?;
+
+        Ok(content.lines().map(|hash| hash.to_string()).collect())
+    }
+
+    async fn save_empty_files(&self, empty_files: &HashSet<String>) -> Result<()> {
+        let content: Vec<&str> = empty_files.iter().map(|hash| hash.as_str()).collect();
+        self.webdav_client
+            .put(&self.empty_files_path(), content.join("\n").into_bytes())
+            .await?;
+
+        Ok(())
+    }
+
+    /// The WebDAV endpoint itself, which needs credentials and is never
+    /// handed to players.
+    fn endpoint_url(&self, path: &str) -> String {
+        format!(
+            "{}{}",
+            self.storage_config.endpoint.trim_end_matches('/'),
+            path
+        )
+    }
+
+    /// Streams the file from WebDAV, forwarding `range`, for storages players
+    /// can't reach directly.
+    async fn proxy(&self, path: &str, range: Option<&str>) -> Result<FileResponse> {
+        let mut request = self.http_client.get(self.endpoint_url(path)).basic_auth(
+            &self.storage_config.username,
+            Some(&self.storage_config.password),
+        );
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let response = request.send().await?;
+        let content_range = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .map(|content_range| content_range.to_string());
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(FileResponse::NotFound),
+            // The player asked for a bad range, which isn't an upstream error.
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                return Ok(FileResponse::RangeNotSatisfiable { content_range });
+            }
+            _ => {}
+        }
+        let response = response.error_for_status()?;
+        let content_range =
+            content_range.filter(|_| response.status() == StatusCode::PARTIAL_CONTENT);
+
+        Ok(FileResponse::Stream {
+            size: response.content_length(),
+            content_range,
+            body: response.bytes_stream().map_err(io::Error::other).boxed(),
+        })
+    }
+
+    /// Stats a single file or folder with a `Depth: 0` PROPFIND.
+    async fn stat(&self, path: &str) -> Result<Option<WebdavStat>> {
+        let entities = match self.webdav_client.list(path, Depth::Number(0)).await {
+            Err(reqwest_dav::Error::Decode(DecodeError::StatusMismatched(err)))
+                if err.response_code == 404 =>
+            {
+                return Ok(None);
+            }
+            result => result?,
+        };
+
+        Ok(entities.into_iter().next().map(WebdavStat::from))
+    }
+
+    /// Stats every file directly inside `dir` with a single `Depth: 1`
+    /// PROPFIND.
+    async fn stat_files(&self, dir: &str) -> Result<Vec<WebdavStat>> {
+        Ok(self
+            .webdav_client
+            .list(dir, Depth::Number(1))
+            .await?
+            .into_iter()
+            .filter(|entity| matches!(entity, ListEntity::File(_)))
+            .map(WebdavStat::from)
+            .collect())
+    }
+
+    async fn verify_remote(&self, href: &str, hash: &str) -> Result<()> {
+        let stream = self
+            .http_client
+            .get(self.endpoint_url(href))
+            .basic_auth(
+                &self.storage_config.username,
+                Some(&self.storage_config.password),
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes_stream();
+
+        verify_stream(stream, hash).await
+    }
+
     async fn get_local_and_remote_files(
         &self,
         files: Vec<BMCLAPIFile>,
-    ) -> Result<(
-        BTreeMap<String, Vec<ListFile>>,
-        HashMap<String, BMCLAPIFile>,
-    )> {
-        let self_files = self.files.lock().await;
-        let remote_files: HashMap<String, BMCLAPIFile> = files
-            .into_iter()
-            .filter_map(|file| {
-                if self_files.contains_key(&file.hash) {
-                    return None;
-                }
-                Some((file.hash.clone(), file))
-            })
-            .collect();
+    ) -> Result<(BTreeMap<String, Vec<WebdavStat>>, Vec<BMCLAPIFile>)> {
+        let files = self.files.unknown(files).await;
+        // `serve` takes the same lock, so release it before any request.
+        let remote_files: Vec<BMCLAPIFile> = {
+            let empty_files = self.empty_files.lock().await;
+            files
+                .into_iter()
+                .filter(|file| !empty_files.contains(&file.hash))
+                .collect()
+        };
 
         let folders: Vec<ListFolder> = self
             .webdav_client
@@ -94,34 +249,22 @@ impl WebdavStorage {
             })
             .collect();
 
-        let mut local_files: BTreeMap<String, Vec<ListFile>> = BTreeMap::new();
+        let mut local_files: BTreeMap<String, Vec<WebdavStat>> = BTreeMap::new();
         let mut tasks = Vec::with_capacity(folders.len());
 
         for folder in folders {
-            let webdav_client = self.webdav_client.clone();
-            let depth = Depth::Number(1);
+            let storage = self.clone();
 
             tasks.push(tokio::spawn(async move {
                 let basename = path_basename(&folder.href).unwrap();
-                let files: Vec<ListFile> = webdav_client
-                    .list(&folder.href, depth)
-                    .await?
-                    .into_iter()
-                    .filter_map(|entity| {
-                        if let ListEntity::File(file) = entity {
-                            Some(file)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
+                let files = storage.stat_files(&folder.href).await?;
 
                 Ok((basename.to_owned(), files))
             }));
         }
 
         for task in tasks {
-            let (folder, files) = task.await.unwrap().unwrap();
+            let (folder, files) = task.await??;
             trace!("Listed files in folder: {}", folder);
             local_files.insert(folder, files);
         }
@@ -145,12 +288,26 @@ impl Storage for WebdavStorage {
                 .mkcol(&self.download_basepath_with_dav_basepath())
                 .await?;
         }
+        let empty_files = self.load_empty_files().await?;
+        if !empty_files.is_empty() {
+            info!("Loaded {} empty files", empty_files.len());
+        }
+        *self.empty_files.lock().await = empty_files;
         info!("Init success");
 
         Ok(())
     }
 
     async fn validate(&self) -> Result<()> {
+        // Players can't authenticate against WebDAV, so redirects need a URL
+        // that works without credentials.
+        if let (ServeMode::Redirect, None) = (
+            self.storage_config.serve_mode,
+            &self.storage_config.public_download_url,
+        ) {
+            error!("`public_download_url` must be set unless `serve_mode` is \"proxy\"");
+            bail!("`public_download_url` is required for redirects");
+        }
         let temp_file_path = Path::new(&self.download_basepath_with_dav_basepath())
             .join(".check")
             .to_string_lossy()
@@ -174,9 +331,12 @@ impl Storage for WebdavStorage {
         Ok(())
     }
 
-    async fn write(&mut self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
-        if content.len() == 0 {
-            self.empty_files.push(file.hash);
+    async fn write(&self, path: &str, content: &[u8], file: BMCLAPIFile) -> Result<()> {
+        if content.is_empty() {
+            let mut empty_files = self.empty_files.lock().await;
+            if empty_files.insert(file.hash) {
+                self.save_empty_files(&empty_files).await?;
+            }
             return Ok(());
         }
         let file_path = Path::new(&self.download_basepath_with_dav_basepath())
@@ -184,89 +344,68 @@ impl Storage for WebdavStorage {
             .to_string_lossy()
             .to_string();
         self.webdav_client.put(&file_path, content.to_vec()).await?;
-        self.files.lock().await.insert(
-            file.hash,
-            WebdavFile {
-                size: content.len(),
-                path: file.path,
-            },
-        );
+        self.files.insert(file.hash, content.len() as u64).await;
 
         Ok(())
     }
 
     async fn exists(&self, path: &str) -> bool {
-        self.webdav_client.get(path).await.is_ok()
+        self.stat(path).await.is_ok_and(|stat| stat.is_some())
     }
 
     async fn get_absolute_path(&self, path: &str) -> String {
-        let protocol = if self.storage_config.endpoint.starts_with("https") {
-            "https"
-        } else {
-            "http"
+        let Some(public_download_url) = &self.storage_config.public_download_url else {
+            return self.endpoint_url(
+                &Path::new(&self.download_basepath_with_dav_basepath())
+                    .join(path)
+                    .to_string_lossy(),
+            );
         };
-        let auth = format!(
-            "{}:{}",
-            self.storage_config.username, self.storage_config.password
-        );
-        let regexp = Regex::new(&format!("^({protocol}?://)")).unwrap();
-        let url = Path::new(
-            &regexp
-                .replace(&self.storage_config.endpoint, format!("$1{auth}@").as_str())
-                .to_string(),
-        )
-        .join(&self.download_basepath_with_dav_basepath())
-        .join(path)
-        .to_string_lossy()
-        .to_string();
+        let url = format!("{}/{}", public_download_url.trim_end_matches('/'), path);
 
-        url
+        match &self.storage_config.redirect_sign {
+            None => url,
+            Some(RedirectSign::Alist { token, expires }) => {
+                let sign = alist_sign(&alist_path(&url), token, *expires);
+                format!("{url}?sign={sign}")
+            }
+        }
     }
 
     async fn check_missing_files(&self, files: Vec<BMCLAPIFile>) -> Result<Vec<BMCLAPIFile>> {
         let (local_files, remote_files) = self.get_local_and_remote_files(files).await?;
-
-        let tasks = local_files.into_iter().map(|(folder, files)| {
-            let clone = self.clone();
-            let remote_files = remote_files.clone();
-            tokio::spawn(async move {
-                trace!("Checking folder: {}", folder);
-                let mut files_to_remove = vec![];
-                for file in files {
-                    let basename = path_basename(&file.href).unwrap();
-                    if let Some(remote_file) = remote_files.get(basename) {
-                        let file_size = file.content_length as usize;
-                        if remote_file.size == file_size {
-                            clone.files.lock().await.insert(
-                                basename.to_string(),
-                                WebdavFile {
-                                    size: file_size,
-                                    path: file.href.clone(),
-                                },
-                            );
-                            files_to_remove.push(basename.to_owned());
-                        }
-                    }
-                }
-                trace!("Checked folder: {}", folder);
-
-                files_to_remove
-            })
-        });
-
-        // TODO
-        let mut remote_files = remote_files.clone();
-        for task in tasks {
-            let files_to_remove = task.await?;
-            for file in files_to_remove {
-                remote_files.remove(&file);
+        let stats: HashMap<String, WebdavStat> = local_files
+            .into_values()
+            .flatten()
+            .filter_map(|stat| Some((path_basename(&stat.href)?.to_string(), stat)))
+            .collect();
+        let stored: HashMap<String, u64> = stats
+            .iter()
+            .map(|(hash, stat)| (hash.clone(), stat.size))
+            .collect();
+        let stats = &stats;
+        let missing_files = self
+            .files
+            .check_missing(
+                remote_files,
+                &stored,
+                self.storage_config.check_mode,
+                |hash| async move { self.verify_remote(&stats[&hash].href, &hash).await },
+            )
+            .await;
+        for file in &missing_files {
+            if let Some(stat) = stats.get(&file.hash) {
+                debug!(
+                    "{} not present, stored {} bytes modified at {}, expected {} bytes",
+                    file.hash, stat.size, stat.mtime, file.size
+                );
             }
         }
 
-        Ok(remote_files.into_iter().map(|f| f.1).collect())
+        Ok(missing_files)
     }
 
-    async fn cleanup_unused_files(&mut self, files: Vec<BMCLAPIFile>) -> Result<()> {
+    async fn cleanup_unused_files(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
         let remote_file_hashes: HashSet<String> =
             // TODO: No more clones
             files.clone().into_iter().map(|file| file.hash).collect();
@@ -291,10 +430,101 @@ impl Storage for WebdavStorage {
             let files = task.await?;
             for (href, hash) in files {
                 self.webdav_client.delete(&href).await?;
-                self.files.lock().await.remove(&hash);
+                self.files.remove([hash]).await;
             }
         }
 
         Ok(())
     }
+
+    async fn serve(&self, hash: &str, range: Option<&str>) -> Result<FileResponse> {
+        if self.empty_files.lock().await.contains(hash) {
+            return Ok(FileResponse::Stream {
+                body: stream::empty().boxed(),
+                size: Some(0),
+                content_range: None,
+            });
+        }
+        let Some(size) = self.files.get(hash).await else {
+            return Ok(FileResponse::NotFound);
+        };
+        if let ServeMode::Proxy = self.storage_config.serve_mode {
+            let path = Path::new(&self.download_basepath_with_dav_basepath())
+                .join(hash_to_filename(hash))
+                .to_string_lossy()
+                .to_string();
+            return self.proxy(&path, range).await;
+        }
+
+        Ok(FileResponse::Redirect {
+            url: self.get_absolute_path(&hash_to_filename(hash)).await,
+            size,
+        })
+    }
+
+    async fn measure(&self, size: u32) -> Result<FileResponse> {
+        let Some(measure_basepath) = &self.storage_config.measure_basepath else {
+            return Ok(measure_response(size));
+        };
+        // Proxied traffic goes through this node, so measure it instead.
+        if let ServeMode::Proxy = self.storage_config.serve_mode {
+            return Ok(measure_response(size));
+        }
+        let path = Path::new(measure_basepath)
+            .join(size.to_string())
+            .to_string_lossy()
+            .to_string();
+
+        Ok(FileResponse::Redirect {
+            url: self.get_absolute_path(&path).await,
+            size: size as u64 * 1024 * 1024,
+        })
+    }
+}
+
+/// Alist serves `/d/<path>` and signs the decoded `/<path>`.
+fn alist_path(url: &str) -> String {
+    Url::parse(url)
+        .map(|url| {
+            let path = url.path();
+            let path = match path.strip_prefix("/d/") {
+                Some(path) => format!("/{path}"),
+                None => path.to_string(),
+            };
+            percent_decode_str(&path).decode_utf8_lossy().to_string()
+        })
+        .unwrap_or_else(|_| url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alist_path_strips_download_prefix() {
+        assert_eq!(
+            alist_path("https://alist.example.com/d/openbmclapi/ab/abcdef"),
+            "/openbmclapi/ab/abcdef"
+        );
+    }
+
+    #[test]
+    fn alist_path_keeps_other_paths_starting_with_d() {
+        assert_eq!(
+            alist_path("https://alist.example.com/dav/ab/abcdef"),
+            "/dav/ab/abcdef"
+        );
+        assert_eq!(
+            alist_path("https://alist.example.com/download/ab/abcdef"),
+            "/download/ab/abcdef"
+        );
+    }
+
+    #[test]
+    fn alist_path_decodes_percent_encoding() {
+        assert_eq!(
+            alist_path("https://alist.example.com/d/my%20files/%E6%96%87%E4%BB%B6/ab"),
+            "/my files/文件/ab"
+        );
+    }
 }
@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use futures_util::{stream, StreamExt};
+use reqwest::Client;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::file_list::{self, fetch_file_list};
+use crate::hash::HashVerifier;
+use crate::storage::{BMCLAPIFile, Storage};
+use crate::utils::hash_to_filename;
+
+const MAX_RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+pub struct Syncer {
+    client: Client,
+    /// Latest token from the `TokenManager`.
+    token_rx: watch::Receiver<String>,
+    storage: Arc<dyn Storage>,
+    concurrency: usize,
+}
+
+impl Syncer {
+    pub fn new(
+        client: Client,
+        token_rx: watch::Receiver<String>,
+        storage: Arc<dyn Storage>,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            client,
+            token_rx,
+            storage,
+            concurrency,
+        }
+    }
+
+    pub async fn sync(&self, files: Vec<BMCLAPIFile>) -> Result<()> {
+        info!("Checking missing files");
+        let missing_files = self.storage.check_missing_files(files).await?;
+        if missing_files.is_empty() {
+            info!("All files are up to date");
+            return Ok(());
+        }
+
+        let total = missing_files.len();
+        let total_bytes: usize = missing_files.iter().map(|file| file.size).sum();
+        info!("Syncing {total} missing files ({total_bytes} bytes)");
+
+        let started_at = Instant::now();
+        let finished = AtomicUsize::new(0);
+        let results: Vec<(BMCLAPIFile, Result<()>)> = stream::iter(missing_files)
+            .map(|file| {
+                let finished = &finished;
+                async move {
+                    let result = self.download_with_retries(&file).await;
+                    let finished = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                    if finished % 100 == 0 || finished == total {
+                        info!("Sync progress: {finished}/{total}");
+                    }
+
+                    (file, result)
+                }
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        let mut failed = 0;
+        let mut downloaded_bytes = 0;
+        for (file, result) in results {
+            match result {
+                Ok(()) => downloaded_bytes += file.size,
+                Err(err) => {
+                    failed += 1;
+                    warn!("Failed to sync {}: {}", file.path, err);
+                }
+            }
+        }
+        info!(
+            "Sync finished in {:?}: {} downloaded ({} bytes), {} failed",
+            started_at.elapsed(),
+            total - failed,
+            downloaded_bytes,
+            failed,
+        );
+        if failed > 0 {
+            bail!("Failed to sync {failed} of {total} files");
+        }
+
+        Ok(())
+    }
+
+    /// Periodically fetches the files changed since `last_modified` and
+    /// downloads the missing ones while serving continues. Fetches the full
+    /// list instead while a storage needs a full check.
+    pub fn start_scheduler(
+        self: Arc<Self>,
+        interval: Duration,
+        mut last_modified: Option<u64>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let token = self.token_rx.borrow().clone();
+                let since = if self.storage.needs_full_check() {
+                    None
+                } else {
+                    last_modified
+                };
+                let files = match fetch_file_list(&self.client, &token, since).await {
+                    Ok(files) => files,
+                    Err(err) => {
+                        warn!("Failed to fetch file list: {}", err);
+                        continue;
+                    }
+                };
+                if files.is_empty() {
+                    debug!("No new files since {:?}", since);
+                    continue;
+                }
+
+                info!("{} files changed since {:?}", files.len(), since);
+                let next_last_modified = file_list::last_modified(&files).max(last_modified);
+                match self.sync(files).await {
+                    Ok(()) => last_modified = next_last_modified,
+                    Err(err) => warn!("Failed to sync new files: {}", err),
+                }
+            }
+        })
+    }
+
+    async fn download_with_retries(&self, file: &BMCLAPIFile) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.download(file).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < MAX_RETRIES => {
+                    debug!(
+                        "Failed to download {} (attempt {attempt}/{MAX_RETRIES}): {}",
+                        file.path, err
+                    );
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn download(&self, file: &BMCLAPIFile) -> Result<()> {
+        let mut stream = self
+            .client
+            .get(format!("/openbmclapi/download/{}", file.hash))
+            .bearer_auth(self.token_rx.borrow().clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes_stream();
+        let mut verifier = HashVerifier::new(&file.hash)?;
+        let mut content = Vec::with_capacity(file.size);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            verifier.update(&chunk);
+            content.extend_from_slice(&chunk);
+        }
+        if content.len() != file.size {
+            bail!(
+                "Size mismatch: expected {} bytes, got {}",
+                file.size,
+                content.len()
+            );
+        }
+        verifier.verify()?;
+        self.storage
+            .write(&hash_to_filename(&file.hash), &content, file.clone())
+            .await?;
+
+        Ok(())
+    }
+}
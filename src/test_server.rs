@@ -0,0 +1,14 @@
+use salvo::conn::TcpAcceptor;
+use salvo::prelude::*;
+
+/// Serves `handler` for every path on an ephemeral local port, returning the
+/// base URL to point clients at.
+pub async fn start(handler: impl Handler) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = TcpAcceptor::try_from(listener).unwrap();
+    let router = Router::with_path("<**rest>").goal(handler);
+    tokio::spawn(Server::new(acceptor).serve(router));
+
+    format!("http://{addr}")
+}
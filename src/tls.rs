@@ -0,0 +1,163 @@
+#[cfg(unix)]
+use std::fs::Permissions;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use salvo::conn::rustls::{Keycert, RustlsConfig};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use x509_parser::pem::parse_x509_pem;
+
+use crate::cluster::Cluster;
+use crate::config::ByocConfig;
+
+const SSL_DIR: &str = ".ssl";
+const RENEW_BEFORE: Duration = Duration::from_secs(24 * 60 * 60);
+const RENEW_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const BYOC_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct CertKeyPair {
+    pub cert: String,
+    pub key: String,
+}
+
+impl CertKeyPair {
+    pub fn not_after(&self) -> Result<SystemTime> {
+        let (_, pem) = parse_x509_pem(self.cert.as_bytes())
+            .map_err(|err| anyhow!("Failed to parse certificate: {err}"))?;
+        let cert = pem
+            .parse_x509()
+            .map_err(|err| anyhow!("Failed to parse certificate: {err}"))?;
+        let not_after = cert.validity().not_after.timestamp();
+
+        Ok(UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64))
+    }
+
+    pub async fn load(byoc: &ByocConfig) -> Result<Self> {
+        Ok(Self {
+            cert: tokio::fs::read_to_string(&byoc.cert).await?,
+            key: tokio::fs::read_to_string(&byoc.key).await?,
+        })
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let ssl_dir = Path::new(SSL_DIR);
+        tokio::fs::create_dir_all(ssl_dir).await?;
+        tokio::fs::write(ssl_dir.join("cert.pem"), &self.cert).await?;
+        Self::write_private(&ssl_dir.join("key.pem"), &self.key).await?;
+
+        Ok(())
+    }
+
+    /// Writes the private key readable by the owner only.
+    async fn write_private(path: &Path, content: &str) -> Result<()> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path).await?;
+        // `mode` only applies to new files, so tighten existing ones as well.
+        #[cfg(unix)]
+        file.set_permissions(Permissions::from_mode(0o600)).await?;
+        file.write_all(content.as_bytes()).await?;
+        file.sync_all().await?;
+
+        Ok(())
+    }
+
+    pub fn rustls_config(&self) -> RustlsConfig {
+        RustlsConfig::new(
+            Keycert::new()
+                .cert(self.cert.as_bytes())
+                .key(self.key.as_bytes()),
+        )
+    }
+}
+
+pub async fn request_cert(cluster: &Cluster) -> Result<CertKeyPair> {
+    let cert = cluster.request_cert().await?;
+    cert.save().await?;
+    info!("Certificate saved to {SSL_DIR}");
+
+    Ok(cert)
+}
+
+/// Re-requests the certificate from the center a day before it expires and
+/// publishes it to the listener, which reloads it without a restart.
+pub fn start_cert_renewal(
+    cluster: Arc<Cluster>,
+    cert_tx: watch::Sender<CertKeyPair>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let renew_in = match cert_tx.borrow().not_after() {
+                Ok(not_after) => not_after
+                    .checked_sub(RENEW_BEFORE)
+                    .and_then(|renew_at| renew_at.duration_since(SystemTime::now()).ok())
+                    .unwrap_or_default()
+                    .max(RENEW_RETRY_DELAY),
+                Err(err) => {
+                    warn!("{}", err);
+                    RENEW_RETRY_DELAY
+                }
+            };
+            info!("Renewing certificate in {:?}", renew_in);
+            tokio::time::sleep(renew_in).await;
+
+            match request_cert(&cluster).await {
+                Ok(cert) => {
+                    info!("Certificate renewed");
+                    cert_tx.send_replace(cert);
+                }
+                Err(err) => {
+                    warn!("Failed to renew certificate: {}", err);
+                    tokio::time::sleep(RENEW_RETRY_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+async fn byoc_modified(byoc: &ByocConfig) -> Result<(SystemTime, SystemTime)> {
+    Ok((
+        tokio::fs::metadata(&byoc.cert).await?.modified()?,
+        tokio::fs::metadata(&byoc.key).await?.modified()?,
+    ))
+}
+
+/// Polls the certificate and key files and reloads them when either changes.
+pub fn start_byoc_watcher(byoc: ByocConfig, cert_tx: watch::Sender<CertKeyPair>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_modified = byoc_modified(&byoc).await.ok();
+        loop {
+            tokio::time::sleep(BYOC_POLL_INTERVAL).await;
+            let modified = match byoc_modified(&byoc).await {
+                Ok(modified) => modified,
+                Err(err) => {
+                    warn!("Failed to stat certificate files: {}", err);
+                    continue;
+                }
+            };
+            if last_modified == Some(modified) {
+                continue;
+            }
+
+            let cert = CertKeyPair::load(&byoc).await;
+            match cert.and_then(|cert| cert.not_after().map(|_| cert)) {
+                Ok(cert) => {
+                    info!("Certificate files changed, reloading");
+                    cert_tx.send_replace(cert);
+                    last_modified = Some(modified);
+                }
+                Err(err) => warn!("Failed to reload certificate: {}", err),
+            }
+        }
+    })
+}
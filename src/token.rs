@@ -1,14 +1,19 @@
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::sync::Arc;
 use std::time::Duration;
 
-use async_cell::sync::AsyncCell;
-use reqwest::{Client, ClientBuilder};
+use anyhow::Result;
+use reqwest::Client;
 use ring::hmac;
 use serde::Deserialize;
 use serde_json::json;
-use tracing::{debug, trace};
+use tokio::sync::watch;
+use tracing::{debug, trace, warn};
 
-use crate::USER_AGENT;
+use crate::utils::build_client;
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Deserialize)]
 struct ChallengeResponse {
@@ -21,40 +26,51 @@ struct TokenResponse {
     ttl: u64,
 }
 
-pub struct TokenManager<'a> {
-    cluster_id: &'a str,
-    cluster_secret: &'a str,
-    token: AsyncCell<Option<String>>,
+pub struct TokenManager {
+    cluster_id: String,
+    cluster_secret: String,
+    token_tx: watch::Sender<String>,
     reqwest_client: Client,
 }
 
-impl<'a> TokenManager<'a> {
-    pub fn new(cluster_id: &'a str, cluster_secret: &'a str, base_url: &'a str) -> Self {
-        let reqwest_client = ClientBuilder::new()
-            .base_url(base_url.to_string())
-            .user_agent(USER_AGENT)
-            .build()
-            .unwrap();
-
-        Self {
-            cluster_id,
-            cluster_secret,
-            token: AsyncCell::new(),
-            reqwest_client,
-        }
+impl TokenManager {
+    /// Fetches the initial token and keeps it refreshed in a background task.
+    pub async fn new(cluster_id: &str, cluster_secret: &str, base_url: &str) -> Result<Arc<Self>> {
+        let (token_tx, _) = watch::channel(String::new());
+        let token_manager = Arc::new(Self {
+            cluster_id: cluster_id.to_string(),
+            cluster_secret: cluster_secret.to_string(),
+            token_tx,
+            reqwest_client: build_client(base_url),
+        });
+
+        let token_response = token_manager.fetch_token().await?;
+        token_manager.token_tx.send_replace(token_response.token);
+        tokio::spawn(token_manager.clone().refresh_loop(token_response.ttl));
+
+        Ok(token_manager)
+    }
+
+    pub fn current_token(&self) -> String {
+        self.token_tx.borrow().clone()
     }
 
-    pub async fn fetch_token(&self) -> Result<String, reqwest::Error> {
+    pub fn subscribe(&self) -> watch::Receiver<String> {
+        self.token_tx.subscribe()
+    }
+
+    async fn fetch_token(&self) -> Result<TokenResponse> {
         let challenge_response: ChallengeResponse = self
             .reqwest_client
             .get("/openbmclapi-agent/challenge")
             .query(&[("clusterId", &self.cluster_id)])
             .send()
             .await?
+            .error_for_status()?
             .json()
             .await?;
 
-        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.cluster_secret.as_bytes());
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.cluster_secret.as_bytes());
         let tag = hmac::sign(&key, challenge_response.challenge.as_bytes());
         let signature = hex::encode(tag.as_ref());
         let token_request_body = json!({
@@ -62,54 +78,83 @@ impl<'a> TokenManager<'a> {
             "challenge": challenge_response.challenge,
             "signature": signature,
         });
-        let token_response: TokenResponse = self
+
+        Ok(self
             .reqwest_client
             .post("/openbmclapi-agent/token")
             .json(&token_request_body)
             .send()
             .await?
+            .error_for_status()?
             .json()
-            .await?;
-
-        self.schedule_refresh_token(token_response.ttl).await;
-
-        Ok(token_response.token)
-    }
-
-    async fn schedule_refresh_token(&self, ttl: u64) {
-        let sleep_time = max(
-            Duration::from_millis(ttl) - Duration::from_secs(600),
-            Duration::from_millis(ttl / 2),
-        );
-        tokio::time::sleep(sleep_time).await;
-        self.get_refreshed_token().await.unwrap();
-
-        trace!("Scheduled refresh token in {:?}ms", sleep_time.as_millis());
+            .await?)
     }
 
-    #[async_recursion::async_recursion]
-    async fn get_refreshed_token(&self) -> Result<(), reqwest::Error> {
-        let token = self.token.get().await;
+    async fn get_refreshed_token(&self) -> Result<TokenResponse> {
         let token_request_body = json!({
             "clusterId": &self.cluster_id,
-            "token": token
+            "token": self.current_token(),
         });
 
-        let token_response: TokenResponse = self
+        Ok(self
             .reqwest_client
             .post("/openbmclapi-agent/token")
             .json(&token_request_body)
             .send()
             .await?
+            .error_for_status()?
             .json()
-            .await?;
-
-        self.token.set(Some(token_response.token));
+            .await?)
+    }
 
+    /// Refreshes the token right away, e.g. before reconnecting to the center.
+    pub async fn refresh(&self) -> Result<String> {
+        let token_response = self.request_token().await?;
+        self.token_tx.send_replace(token_response.token.clone());
         debug!("Successfully refreshed token");
 
-        self.schedule_refresh_token(token_response.ttl).await;
+        Ok(token_response.token)
+    }
+
+    async fn request_token(&self) -> Result<TokenResponse> {
+        // Fall back to a fresh challenge if the current token was rejected,
+        // e.g. because it already expired.
+        match self.get_refreshed_token().await {
+            Ok(token_response) => Ok(token_response),
+            Err(err) => {
+                debug!("Failed to refresh token, requesting a new one: {}", err);
+                self.fetch_token().await
+            }
+        }
+    }
 
-        Ok(())
+    async fn refresh_loop(self: Arc<Self>, mut ttl: u64) {
+        loop {
+            let sleep_time = max(
+                Duration::from_millis(ttl).saturating_sub(Duration::from_secs(600)),
+                Duration::from_millis(ttl / 2),
+            );
+            trace!("Scheduled refresh token in {:?}ms", sleep_time.as_millis());
+            tokio::time::sleep(sleep_time).await;
+
+            let mut retry_delay = MIN_RETRY_DELAY;
+            let token_response = loop {
+                match self.request_token().await {
+                    Ok(token_response) => break token_response,
+                    Err(err) => {
+                        warn!(
+                            "Failed to refresh token, retrying in {:?}: {}",
+                            retry_delay, err
+                        );
+                        tokio::time::sleep(retry_delay).await;
+                        retry_delay = min(retry_delay * 2, MAX_RETRY_DELAY);
+                    }
+                }
+            };
+
+            self.token_tx.send_replace(token_response.token);
+            ttl = token_response.ttl;
+            debug!("Successfully refreshed token");
+        }
     }
 }
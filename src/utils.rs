@@ -1,5 +1,21 @@
 use std::path::Path;
 
+use reqwest::{Client, ClientBuilder};
+
+use crate::USER_AGENT;
+
 pub fn path_basename(path: &str) -> Option<&str> {
     Path::new(path).file_name().unwrap().to_str()
 }
+
+pub fn build_client(base_url: &str) -> Client {
+    ClientBuilder::new()
+        .base_url(base_url.to_string())
+        .user_agent(USER_AGENT)
+        .build()
+        .unwrap()
+}
+
+pub fn hash_to_filename(hash: &str) -> String {
+    format!("{}/{}", &hash[..2], hash)
+}